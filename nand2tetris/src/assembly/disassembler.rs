@@ -0,0 +1,170 @@
+use std::{collections::BTreeMap, io::Write};
+
+use crate::{diagnostic::Diagnostic, hex::Hex, symbols::SymbolTable};
+
+use super::{Instruction, Jump, LoadData, Target};
+
+/// Machine code decoded back into Hack assembly.
+pub struct Disassembly {
+    /// Every word of the program and its decoding, `None` if it is not a valid instruction
+    words: Vec<(u16, Option<Instruction>)>,
+    /// Known or synthesized labels by ROM address
+    labels: BTreeMap<u16, Vec<String>>,
+}
+
+impl Disassembly {
+    /// Decodes the program, naming addresses after the symbol table where it knows them.
+    pub fn new(hex: &Hex, symbols: &SymbolTable) -> Self {
+        let mut words: Vec<_> = hex
+            .instructions
+            .iter()
+            .map(|&word| (word, Instruction::decode(word)))
+            .collect();
+
+        let mut labels: BTreeMap<u16, Vec<String>> = BTreeMap::new();
+        for symbol in symbols.symbols.iter().filter(|s| s.kind.is_rom()) {
+            labels
+                .entry(symbol.value)
+                .or_default()
+                .push(symbol.name.clone());
+        }
+
+        // An address loaded right before a jump is a jump target, and one loaded right before
+        // a memory access is a variable. Other loads are left alone, the same number may well
+        // be data.
+        for i in 1..words.len() {
+            let (is_jump, uses_memory) = match &words[i].1 {
+                Some(Instruction::Command {
+                    compute,
+                    target,
+                    jump,
+                }) => (
+                    !matches!(jump, Jump::NONE),
+                    compute.reads().contains(Target::M) || target.contains(Target::M),
+                ),
+                _ => (false, false),
+            };
+            let Some(Instruction::Load { data }) = &mut words[i - 1].1 else {
+                continue;
+            };
+            let LoadData::Data(address) = *data else {
+                continue;
+            };
+            if is_jump && (address as usize) <= hex.instructions.len() {
+                let names = labels
+                    .entry(address)
+                    .or_insert_with(|| vec![format!("L{}", address)]);
+                *data = LoadData::Label(names[0].clone());
+            } else if uses_memory && let Some(name) = symbols.variable(address) {
+                *data = LoadData::Label(name.to_string());
+            }
+        }
+        Disassembly { words, labels }
+    }
+
+    /// A warning for every word that does not decode to an instruction.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.words
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, instruction))| instruction.is_none())
+            .map(|(address, (word, _))| {
+                Diagnostic::warning(
+                    "invalid-instruction",
+                    format!(
+                        "Word {:016b} at address {} is not a valid Hack instruction",
+                        word, address
+                    ),
+                )
+                .with_note(
+                    "C-instructions start with 111 and use one of the 28 defined computations",
+                )
+            })
+            .collect()
+    }
+
+    /// Writes the program in the same layout as `Assembly::write`. Invalid words are kept as
+    /// comments so the addresses of the remaining lines can still be followed.
+    pub fn write(&self, out: &mut impl Write) -> std::io::Result<()> {
+        for (address, (word, instruction)) in self.words.iter().enumerate() {
+            for label in self.labels.get(&(address as u16)).into_iter().flatten() {
+                writeln!(out, "({})", label)?;
+            }
+            match instruction {
+                Some(instruction) => writeln!(out, "    {}", instruction)?,
+                None => writeln!(out, "    // invalid instruction {:016b}", word)?,
+            }
+        }
+        // Labels past the end of the program
+        for label in self
+            .labels
+            .range(self.words.len() as u16..)
+            .flat_map(|(_, l)| l)
+        {
+            writeln!(out, "({})", label)?;
+        }
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use crate::{
+        assembly::{Assembly, linker::link},
+        hex::Hex,
+        symbols::SymbolTable,
+    };
+
+    use super::Disassembly;
+
+    fn assemble(path: &Path) -> Vec<u16> {
+        let object = Assembly::from_file(path).unwrap().object().unwrap();
+        link(&[object]).unwrap().0.instructions
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../projects/6");
+        let out = std::env::temp_dir().join(format!("disasm-{}", std::process::id()));
+        fs::create_dir_all(&out).unwrap();
+        for program in ["add/Add", "max/Max", "rect/Rect", "pong/Pong"] {
+            let instructions = assemble(&dir.join(program).with_extension("asm"));
+            let disassembly = Disassembly::new(
+                &Hex {
+                    instructions: instructions.clone(),
+                },
+                &SymbolTable::default(),
+            );
+            assert!(disassembly.diagnostics().is_empty(), "{}", program);
+
+            let mut text = Vec::new();
+            disassembly.write(&mut text).unwrap();
+            let path = out.join(program.replace('/', "-")).with_extension("asm");
+            fs::write(&path, text).unwrap();
+            assert_eq!(assemble(&path), instructions, "{}", program);
+        }
+        fs::remove_dir_all(&out).unwrap();
+    }
+
+    #[test]
+    fn invalid_words() {
+        // A jump to the end of the program, an undefined computation and a C-instruction
+        // without its two unused bits set
+        let disassembly = Disassembly::new(
+            &Hex {
+                instructions: vec![2, 0b1110_1010_1000_0111, 0b1111_1111_1100_0000, 0x8000],
+            },
+            &SymbolTable::default(),
+        );
+        let mut text = Vec::new();
+        disassembly.write(&mut text).unwrap();
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "    @L2\n    0;JMP\n(L2)\n    // invalid instruction 1111111111000000\n    \
+             // invalid instruction 1000000000000000\n"
+        );
+        assert_eq!(disassembly.diagnostics().len(), 2);
+    }
+}
@@ -0,0 +1,221 @@
+use std::{collections::HashMap, fmt::Display};
+
+use crate::diagnostic::Diagnostic;
+
+use super::{LabelStore, MAX_LOAD};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    And,
+    Or,
+}
+
+impl BinOp {
+    /// Binding strength, following C: `* /` before `+ -` before `&` before `|`.
+    fn precedence(&self) -> u8 {
+        match self {
+            BinOp::Or => 0,
+            BinOp::And => 1,
+            BinOp::Add | BinOp::Sub => 2,
+            BinOp::Mul | BinOp::Div => 3,
+        }
+    }
+}
+
+impl Display for BinOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::And => "&",
+            BinOp::Or => "|",
+        })
+    }
+}
+
+/// A constant expression in an A-instruction, evaluated once all labels are known.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Literal(i64),
+    Symbol(String),
+    Binary(Box<Expression>, BinOp, Box<Expression>),
+}
+
+impl Expression {
+    pub(super) fn evaluate(&self, ls: &LabelStore) -> Result<i64, Diagnostic> {
+        match self {
+            Expression::Literal(value) => Ok(*value),
+            Expression::Symbol(name) => ls.lookup(name).map(i64::from).ok_or_else(|| {
+                Diagnostic::error(
+                    "unknown-symbol",
+                    format!("Symbol '{}' has no address yet", name),
+                )
+                .with_note(format!(
+                    "variables are allocated when first used as `@{}`, which has to come before any expression using them",
+                    name
+                ))
+            }),
+            Expression::Binary(left, op, right) => {
+                let (left, right) = (left.evaluate(ls)?, right.evaluate(ls)?);
+                let value = match op {
+                    BinOp::Add => left.checked_add(right),
+                    BinOp::Sub => left.checked_sub(right),
+                    BinOp::Mul => left.checked_mul(right),
+                    BinOp::Div if right == 0 => {
+                        return Err(Diagnostic::error(
+                            "division-by-zero",
+                            format!("Division of {} by zero", left),
+                        ));
+                    }
+                    BinOp::Div => left.checked_div(right),
+                    BinOp::And => Some(left & right),
+                    BinOp::Or => Some(left | right),
+                };
+                // Intermediate results far outside the range of an A-instruction
+                value.ok_or_else(|| {
+                    Diagnostic::error(
+                        "value-too-large",
+                        format!(
+                            "Value of '{}' does not fit in an A-instruction, the maximum is {}",
+                            self, MAX_LOAD
+                        ),
+                    )
+                })
+            }
+        }
+    }
+
+    /// Replaces the symbols that name constants by their values.
+    pub(super) fn substitute(self, constants: &HashMap<String, Expression>) -> Expression {
+        match self {
+            Expression::Symbol(name) => match constants.get(&name) {
+                Some(value) => value.clone(),
+                None => Expression::Symbol(name),
+            },
+            Expression::Binary(left, op, right) => Expression::Binary(
+                Box::new(left.substitute(constants)),
+                op,
+                Box::new(right.substitute(constants)),
+            ),
+            literal => literal,
+        }
+    }
+
+    /// Every symbol the expression refers to.
+    pub(super) fn symbols(&self) -> Vec<&str> {
+        match self {
+            Expression::Literal(_) => Vec::new(),
+            Expression::Symbol(name) => vec![name.as_str()],
+            Expression::Binary(left, _, right) => {
+                let mut symbols = left.symbols();
+                symbols.extend(right.symbols());
+                symbols
+            }
+        }
+    }
+
+    fn fmt_operand(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        parent: BinOp,
+        right: bool,
+    ) -> std::fmt::Result {
+        let parenthesize = match self {
+            Expression::Literal(value) => *value < 0,
+            Expression::Symbol(_) => false,
+            Expression::Binary(_, op, _) => {
+                op.precedence() < parent.precedence()
+                    || (right && op.precedence() == parent.precedence())
+            }
+        };
+        match parenthesize {
+            true => write!(f, "({})", self),
+            false => write!(f, "{}", self),
+        }
+    }
+}
+
+/// Writes the expression with only the parentheses its structure requires.
+impl Display for Expression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expression::Literal(value) => write!(f, "{}", value),
+            Expression::Symbol(name) => f.write_str(name),
+            Expression::Binary(left, op, right) => {
+                left.fmt_operand(f, *op, false)?;
+                write!(f, "{}", op)?;
+                right.fmt_operand(f, *op, true)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use chumsky::Parser;
+
+    use crate::{
+        assembly::{Assembly, parser},
+        diagnostic::Diagnostic,
+    };
+
+    /// The value `@expression` loads into A.
+    fn load(expression: &str) -> Result<u16, Vec<Diagnostic>> {
+        let source = format!("@{}\n", expression);
+        let assembly = Assembly::from_source(&source, Path::new("test.asm"))?;
+        let (words, _) = assembly.assemble()?;
+        Ok(words.into_iter().flatten().next().unwrap())
+    }
+
+    fn error(expression: &str) -> &'static str {
+        load(expression).unwrap_err()[0].code
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(load("1+2*3").unwrap(), 7);
+        assert_eq!(load("(1+2)*3").unwrap(), 9);
+        assert_eq!(load("10-4-3").unwrap(), 3);
+        assert_eq!(load("7/2").unwrap(), 3);
+        assert_eq!(load("6&3|8").unwrap(), 10);
+    }
+
+    #[test]
+    fn symbols() {
+        assert_eq!(load("SCREEN+32").unwrap(), 0x4020);
+        assert_eq!(load("R5*2").unwrap(), 10);
+    }
+
+    #[test]
+    fn out_of_range() {
+        assert_eq!(error("32767+1"), "value-too-large");
+        assert_eq!(error("0-1"), "value-too-large");
+        assert_eq!(error("1/0"), "division-by-zero");
+    }
+
+    #[test]
+    fn overflow() {
+        assert_eq!(error("65535*65535*65535*65535"), "value-too-large");
+        assert_eq!(error("0-65535*65535*65535*65535*65535"), "value-too-large");
+        assert_eq!(
+            error("65535*65535*65535*65535+65535*65535*65535*65535"),
+            "value-too-large"
+        );
+    }
+
+    #[test]
+    fn display() {
+        let parsed = |src: &str| parser::expression().parse(src).into_result().unwrap();
+        for src in ["1+2*3", "(1+2)*3", "1-(2-3)", "a|b&c", "(a|b)&c", "(-1)*x"] {
+            assert_eq!(parsed(src).to_string(), src);
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+
+use crate::{
+    diagnostic::{Diagnostic, Location, suggest},
+    hex::Hex,
+    symbols::{FUNCTION_PREFIX, SymbolTable},
+};
+
+use super::{Instruction, LabelStore, ROM_SIZE, object::Object, rom_overflow};
+
+/// Whether a symbol names a label the VM translator generates, which are never variables:
+/// function entry points and the labels scoped with `$`.
+fn is_generated_label(name: &str) -> bool {
+    name.starts_with(FUNCTION_PREFIX) || name.contains('$')
+}
+
+/// Places the objects in ROM one after another and resolves their relocations. Exported
+/// labels are visible to every object, local labels only to their own. Any other symbol is a
+/// variable, allocated in RAM in order of first use across all objects, except for generated
+/// labels that no object defines. Returns the program with the table of its exported labels
+/// and variables.
+pub fn link(objects: &[Object]) -> Result<(Hex, SymbolTable), Vec<Diagnostic>> {
+    let size: usize = objects.iter().map(|o| o.code.len()).sum();
+    if size > ROM_SIZE {
+        return Err(rom_overflow(size)
+            .with_note(format!("linking {} objects", objects.len()))
+            .into());
+    }
+
+    let mut ls = LabelStore::new();
+    let mut errors = Vec::new();
+
+    let mut bases = Vec::new();
+    let mut base: u16 = 0;
+    let mut exporters: HashMap<&str, &Option<Location>> = HashMap::new();
+    for object in objects {
+        bases.push(base);
+        for export in &object.exports {
+            if let Err(e) = ls.insert(&export.name, base + export.address) {
+                let e = match exporters.get(export.name.as_str()) {
+                    Some(first) => {
+                        let e = Diagnostic::error(
+                            "duplicate-export",
+                            format!("Label '{}' is exported more than once", export.name),
+                        )
+                        .with_label("exported again here");
+                        match first {
+                            Some(first) => e.with_secondary(first.clone(), "first exported here"),
+                            None => e,
+                        }
+                    }
+                    // Clashes with a predefined symbol
+                    None => e,
+                };
+                errors.push(e.or_location(export.location.as_ref()));
+            }
+            exporters.entry(&export.name).or_insert(&export.location);
+        }
+        base += object.code.len() as u16;
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut instructions = Vec::new();
+    for (object, base) in objects.iter().zip(bases) {
+        let mut code = object.code.clone();
+        let hidden: Vec<_> = object
+            .locals
+            .iter()
+            .map(|(name, address)| (name, ls.shadow(name, base + address)))
+            .collect();
+        for relocation in &object.relocations {
+            let unresolved: Vec<_> = relocation
+                .data
+                .symbols()
+                .into_iter()
+                .filter(|symbol| is_generated_label(symbol) && ls.lookup(symbol).is_none())
+                .collect();
+            for symbol in &unresolved {
+                let mut e = Diagnostic::error(
+                    "unresolved-symbol",
+                    format!("Label '{}' is not defined by any object", symbol),
+                )
+                .with_label("referred to here");
+                if let Some(suggestion) = suggest(symbol, exporters.keys().copied()) {
+                    e = e.with_note(suggestion);
+                }
+                errors.push(e.or_location(relocation.location.as_ref()));
+            }
+            if !unresolved.is_empty() {
+                continue;
+            }
+            let instruction = Instruction::Load {
+                data: relocation.data.clone(),
+            };
+            match instruction.compile(&mut ls) {
+                Ok(word) => code[relocation.address as usize] = word.unwrap_or_default(),
+                Err(e) => errors.push(e.or_location(relocation.location.as_ref())),
+            }
+        }
+        for (name, previous) in hidden {
+            ls.restore(name, previous);
+        }
+        instructions.extend(code);
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok((Hex { instructions }, ls.table()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{
+        assembly::{Assembly, object::Object},
+        diagnostic::Diagnostic,
+    };
+
+    use super::link;
+
+    fn object(src: &str) -> Object {
+        Assembly::from_source(src, Path::new("test.asm"))
+            .unwrap()
+            .object()
+            .unwrap()
+    }
+
+    fn errors(sources: &[&str]) -> Vec<Diagnostic> {
+        let objects: Vec<_> = sources.iter().map(|src| object(src)).collect();
+        match link(&objects) {
+            Ok(_) => panic!("linking succeeded"),
+            Err(errors) => errors,
+        }
+    }
+
+    #[test]
+    fn exports() {
+        let main = object("@function:B.f\n0;JMP\n");
+        let callee = object("(function:B.f)\n@function:B.f\n0;JMP\n");
+        let (hex, _) = link(&[main, callee]).unwrap();
+        assert_eq!(hex.instructions[0], 2);
+        assert_eq!(hex.instructions[2], 2);
+    }
+
+    #[test]
+    fn locals_and_variables() {
+        let first = object("(LOOP)\n@counter\nM=M+1\n@LOOP\n0;JMP\n");
+        let second = object("(LOOP)\n@counter\nM=M-1\n@LOOP\n0;JMP\n");
+        let (hex, _) = link(&[first, second]).unwrap();
+        // Each object jumps to its own loop, but both count in the same variable
+        assert_eq!(hex.instructions[2], 0);
+        assert_eq!(hex.instructions[6], 4);
+        assert_eq!(hex.instructions[0], 16);
+        assert_eq!(hex.instructions[4], 16);
+    }
+
+    #[test]
+    fn unresolved_function() {
+        let errors = errors(&["@function:B.g\n0;JMP\n", "(function:B.f)\n0;JMP\n"]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, "unresolved-symbol");
+        assert_eq!(errors[0].notes, ["did you mean function:B.f?"]);
+    }
+
+    #[test]
+    fn unresolved_scoped_label() {
+        let errors = errors(&["@B.f$LOOP\n0;JMP\n", "@x+Main$ret.0\nD=A\n"]);
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(|e| e.code == "unresolved-symbol"));
+    }
+
+    #[test]
+    fn duplicate_export() {
+        let errors = errors(&["(function:B.f)\n0;JMP\n", "(function:B.f)\n0;JMP\n"]);
+        assert_eq!(errors[0].code, "duplicate-export");
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    diagnostic::{Diagnostic, Location},
+    symbols::SymbolKind,
+};
+
+use super::{Assembly, Instruction, Jump, LoadData, PREDEFINED, Target};
+
+/// How an `@symbol` is used by the instruction right after it.
+#[derive(Debug, Default)]
+struct Uses<'a> {
+    jumps: Vec<&'a Option<Location>>,
+    reads: usize,
+    writes: Vec<&'a Option<Location>>,
+}
+
+impl Assembly {
+    /// Looks for symbols that are likely mistakes: jumps to labels that are never defined,
+    /// labels nobody refers to and variables that are written but never read.
+    ///
+    /// An incomplete program, such as an object linked later, may refer to labels exported
+    /// elsewhere and share its variables, so only unused labels are reported for it.
+    pub fn lint(&self, complete: bool) -> Vec<Diagnostic> {
+        let mut labels: HashMap<&str, &Option<Location>> = HashMap::new();
+        let mut uses: HashMap<&str, Uses> = HashMap::new();
+        let mut order = Vec::new();
+        for (i, (instruction, location)) in
+            self.instructions.iter().zip(&self.locations).enumerate()
+        {
+            let symbol = match instruction {
+                Instruction::Label { label } => {
+                    labels.entry(label).or_insert(location);
+                    continue;
+                }
+                Instruction::Load {
+                    data: LoadData::Label(label),
+                } => label.as_str(),
+                Instruction::Load {
+                    data: LoadData::Expression(expression),
+                } => {
+                    // Computing with an address counts as reading it
+                    for symbol in expression.symbols() {
+                        uses.entry(symbol).or_default().reads += 1;
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            if !uses.contains_key(symbol) {
+                order.push(symbol);
+            }
+            let entry = uses.entry(symbol).or_default();
+            match self.instructions.get(i + 1) {
+                Some(Instruction::Command {
+                    compute,
+                    target,
+                    jump,
+                }) => {
+                    if !matches!(jump, Jump::NONE) {
+                        entry.jumps.push(location);
+                    }
+                    if compute.reads().intersects(Target::A | Target::M) {
+                        entry.reads += 1;
+                    }
+                    if target.contains(Target::M) {
+                        entry.writes.push(location);
+                    }
+                }
+                // A label may be jumped to with the address still in A
+                Some(Instruction::Label { label: _ }) => entry.reads += 1,
+                _ => (),
+            }
+        }
+
+        let exported: HashSet<&str> = self.exports.iter().map(|(name, _)| name.as_str()).collect();
+        let predefined = |name: &str| PREDEFINED.iter().any(|(p, _)| *p == name);
+        let mut warnings = Vec::new();
+        let undefined = order
+            .into_iter()
+            .filter(|s| complete && !predefined(s) && !labels.contains_key(s));
+        for symbol in undefined {
+            let use_ = &uses[symbol];
+            if let Some(location) = use_.jumps.first() {
+                warnings.push(
+                    Diagnostic::warning(
+                        "undefined-label",
+                        format!("Jump to '{}', which is never defined as a label", symbol),
+                    )
+                    .or_location(location.as_ref())
+                    .with_label("used as a jump target here")
+                    .with_note(format!(
+                        "undefined symbols become variables, so this jumps to the RAM address of '{}'",
+                        symbol
+                    )),
+                );
+            } else if use_.reads == 0
+                && let Some(location) = use_.writes.first()
+            {
+                warnings.push(
+                    Diagnostic::warning(
+                        "unused-variable",
+                        format!("Variable '{}' is written but never read", symbol),
+                    )
+                    .or_location(location.as_ref())
+                    .with_label("written here"),
+                );
+            }
+        }
+
+        let mut unused: Vec<_> = labels
+            .into_iter()
+            .filter(|(label, _)| {
+                !uses.contains_key(label)
+                    && !exported.contains(label)
+                    && SymbolKind::label(label) == SymbolKind::Label
+            })
+            .collect();
+        unused
+            .sort_by_key(|(_, location)| location.as_ref().map(|l| (l.file.clone(), l.span.start)));
+        for (label, location) in unused {
+            warnings.push(
+                Diagnostic::warning("unused-label", format!("Label '{}' is never used", label))
+                    .or_location(location.as_ref()),
+            );
+        }
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::assembly::Assembly;
+
+    fn lint(src: &str, complete: bool) -> Vec<&'static str> {
+        Assembly::from_source(src, Path::new("test.asm"))
+            .unwrap()
+            .lint(complete)
+            .iter()
+            .map(|w| w.code)
+            .collect()
+    }
+
+    #[test]
+    fn warnings() {
+        assert_eq!(lint("@END\n0;JMP\n", true), ["undefined-label"]);
+        assert_eq!(lint("(UNUSED)\n@1\nD=A\n", true), ["unused-label"]);
+        assert_eq!(lint("@x\nM=1\n@x\nM=0\n", true), ["unused-variable"]);
+    }
+
+    #[test]
+    fn no_warnings() {
+        // A symbol loaded for anything but a jump is a variable
+        assert!(lint("@X\nD=A\n", true).is_empty());
+        assert!(lint("@X\nD=M\n", true).is_empty());
+        assert!(lint("@x\nM=1\n@x\nD=M\n", true).is_empty());
+        assert!(lint("@x+1\nD=A\n@x\nM=D\n", true).is_empty());
+        assert!(lint("(LOOP)\n@LOOP\n0;JMP\n", true).is_empty());
+        assert!(lint("@SCREEN\nM=1\n@KBD\n0;JMP\n", true).is_empty());
+        // Labels the VM translator generates for functions
+        assert!(lint("(function:Main.main)\n@0\nD=A\n", true).is_empty());
+    }
+
+    #[test]
+    fn incomplete_programs() {
+        // Labels and variables may belong to other objects
+        assert_eq!(
+            lint("@END\n0;JMP\n@x\nM=1\n(UNUSED)\n", false),
+            ["unused-label"]
+        );
+    }
+}
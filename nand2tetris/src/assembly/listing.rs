@@ -0,0 +1,162 @@
+use std::{
+    collections::HashMap,
+    fs::{File, read_to_string},
+    io::{BufWriter, Write},
+    path::Path,
+    rc::Rc,
+};
+
+use crate::{
+    diagnostic::{Diagnostic, Location},
+    symbols::SymbolKind,
+};
+
+use super::{Assembly, Instruction, LoadData};
+
+/// Source files loaded on demand to quote the lines instructions stem from.
+#[derive(Default)]
+struct Sources {
+    files: HashMap<Rc<str>, Option<String>>,
+}
+
+impl Sources {
+    /// The 1-based line number and text of the line the location starts in.
+    fn line(&mut self, location: &Location) -> Option<(usize, &str)> {
+        let src = self
+            .files
+            .entry(location.file.clone())
+            .or_insert_with(|| read_to_string(&*location.file).ok())
+            .as_deref()?;
+        let start = src.get(..location.span.start)?;
+        let begin = start.rfind('\n').map_or(0, |i| i + 1);
+        let end = src[begin..].find('\n').map_or(src.len(), |i| begin + i);
+        Some((start.matches('\n').count() + 1, src[begin..end].trim()))
+    }
+}
+
+impl Assembly {
+    /// Writes a listing with the ROM address, encoding and source line of every instruction,
+    /// followed by the symbol table.
+    pub fn write_listing(&self, path: &Path) -> Result<(), Vec<Diagnostic>> {
+        let (words, ls) = self.assemble()?;
+        let file = File::create(path).map_err(|e| Diagnostic::io(path, e))?;
+        let mut out = BufWriter::new(file);
+        let mut sources = Sources::default();
+        let mut result = writeln!(
+            out,
+            "{:>5}  {:<16}  {:<4}  {:<24} {:<8} Source",
+            "ROM", "Binary", "Hex", "Instruction", "Value"
+        );
+
+        let mut address: u16 = 0;
+        let mut last_line = None;
+        for ((instruction, location), word) in
+            self.instructions.iter().zip(&self.locations).zip(words)
+        {
+            let value = match instruction {
+                Instruction::Label { label }
+                | Instruction::Load {
+                    data: LoadData::Label(label),
+                } => ls.labels.get(label).map(|(v, _)| format!("= {}", v)),
+                Instruction::Load {
+                    data: LoadData::Expression(expression),
+                } => expression.evaluate(&ls).ok().map(|v| format!("= {}", v)),
+                _ => None,
+            };
+            // Quote a source line only once, several instructions may stem from it
+            let source = match location.as_ref().and_then(|l| Some((l, sources.line(l)?))) {
+                Some((l, (line, text))) if last_line != Some((l.file.clone(), line)) => {
+                    last_line = Some((l.file.clone(), line));
+                    let name = Path::new(&*l.file).file_name().unwrap_or_default();
+                    format!("{}:{}  {}", name.to_string_lossy(), line, text)
+                }
+                _ => String::new(),
+            };
+            let columns = match word {
+                Some(word) => format!("{:>5}  {:016b}  {:04X}", address, word, word),
+                None => format!("{:>5}  {:16}  {:4}", "", "", ""),
+            };
+            let row = format!(
+                "{}  {:<24} {:<8} {}",
+                columns,
+                instruction.to_string(),
+                value.unwrap_or_default(),
+                source
+            );
+            result = result.and_then(|_| writeln!(out, "{}", row.trim_end()));
+            if word.is_some() {
+                address += 1;
+            }
+        }
+
+        for (title, kind) in [
+            ("Labels", SymbolKind::Label),
+            ("Functions", SymbolKind::Function),
+            ("Variables", SymbolKind::Variable),
+            ("Statics", SymbolKind::Static),
+            ("Predefined symbols", SymbolKind::Predefined),
+        ] {
+            let symbols = ls.symbols(kind);
+            if symbols.is_empty() {
+                continue;
+            }
+            result = result.and_then(|_| writeln!(out, "\n{}:", title));
+            for (name, value) in symbols {
+                result = result
+                    .and_then(|_| writeln!(out, "  {:<32} {:>5}  {:04X}", name, value, value));
+            }
+        }
+        result
+            .and_then(|_| out.flush())
+            .map_err(|e| Diagnostic::io(path, e).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::Assembly;
+
+    #[test]
+    fn golden() {
+        let dir = std::env::temp_dir().join(format!("listing-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("Loop.asm");
+        fs::write(
+            &src,
+            "// Counts down\n(LOOP)\n  @i\n  M=M-1\n  @LOOP\n  D;JGT\n",
+        )
+        .unwrap();
+        let lst = dir.join("Loop.lst");
+        Assembly::from_file(&src)
+            .unwrap()
+            .write_listing(&lst)
+            .unwrap();
+        let listing = fs::read_to_string(&lst).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let expected = "  ROM  Binary            Hex   Instruction              Value    Source
+                               (LOOP)                   = 0      Loop.asm:2  (LOOP)
+    0  0000000000010000  0010  @i                       = 16     Loop.asm:3  @i
+    1  1111110010001000  FC88  M=M-1                             Loop.asm:4  M=M-1
+    2  0000000000000000  0000  @LOOP                    = 0      Loop.asm:5  @LOOP
+    3  1110001100000001  E301  D;JGT                             Loop.asm:6  D;JGT
+
+Labels:
+  LOOP                                 0  0000
+
+Variables:
+  i                                   16  0010
+
+Predefined symbols:
+  R0                                   0  0000
+";
+        assert!(
+            listing.starts_with(expected),
+            "unexpected listing:\n{}",
+            listing
+        );
+        assert!(listing.ends_with("  KBD                              24576  6000\n"));
+    }
+}
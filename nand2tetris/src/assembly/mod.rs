@@ -0,0 +1,1272 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+    rc::Rc,
+};
+
+use bitflags::bitflags;
+use chumsky::Parser;
+
+pub use expression::{BinOp, Expression};
+use object::{Export, Object, Relocation};
+pub use optimizer::Report;
+use parser::Item;
+
+use crate::{
+    CodeType,
+    diagnostic::{Diagnostic, Location, suggest},
+    memory::MemoryMap,
+    symbols::{Symbol, SymbolKind, SymbolTable},
+};
+
+pub mod disassembler;
+mod expression;
+pub mod linker;
+mod lint;
+mod listing;
+pub mod object;
+mod optimizer;
+mod parser;
+mod preprocessor;
+
+/// Largest value an A-instruction can load directly.
+pub const MAX_LOAD: u16 = u16::MAX >> 1;
+/// Number of words the instruction memory holds.
+pub const ROM_SIZE: usize = 0x8000;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct Target: u8 {
+        const A = 0b100;
+        const D = 0b010;
+        const M = 0b001;
+    }
+}
+
+impl TryFrom<&str> for Target {
+    type Error = Diagnostic;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut target = Target::empty();
+        for c in value.chars() {
+            match c {
+                'A' => target |= Target::A,
+                'M' => target |= Target::M,
+                'D' => target |= Target::D,
+                _ => {
+                    return Err(Diagnostic::error(
+                        "unknown-target",
+                        format!("Unknown command target '{}'", c),
+                    )
+                    .with_note("targets are a combination of A, M and D"));
+                }
+            }
+        }
+        Ok(target)
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (flag, name) in [(Target::A, "A"), (Target::M, "M"), (Target::D, "D")] {
+            if self.contains(flag) {
+                f.write_str(name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Target {
+    fn compile(&self) -> u16 {
+        (self.bits() as u16) << 3
+    }
+
+    fn decode(word: u16) -> Self {
+        Target::from_bits_truncate((word >> 3) as u8)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Jump {
+    NONE,
+    JGT,
+    JEQ,
+    JGE,
+    JLT,
+    JNE,
+    JLE,
+    JMP,
+}
+
+impl TryFrom<&str> for Jump {
+    type Error = Diagnostic;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "" => Ok(Jump::NONE),
+            "JGT" => Ok(Jump::JGT),
+            "JEQ" => Ok(Jump::JEQ),
+            "JGE" => Ok(Jump::JGE),
+            "JLT" => Ok(Jump::JLT),
+            "JNE" => Ok(Jump::JNE),
+            "JLE" => Ok(Jump::JLE),
+            "JMP" => Ok(Jump::JMP),
+            _ => {
+                let spellings = ["JGT", "JEQ", "JGE", "JLT", "JNE", "JLE", "JMP"];
+                let mut message = format!("Unknown jump condition '{}'", value);
+                if let Some(suggestion) = suggest(value, spellings) {
+                    message = format!("{}, {}", message, suggestion);
+                }
+                Err(Diagnostic::error("unknown-jump", message))
+            }
+        }
+    }
+}
+
+impl Display for Jump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Jump::NONE => "",
+            Jump::JGT => "JGT",
+            Jump::JEQ => "JEQ",
+            Jump::JGE => "JGE",
+            Jump::JLT => "JLT",
+            Jump::JNE => "JNE",
+            Jump::JLE => "JLE",
+            Jump::JMP => "JMP",
+        })
+    }
+}
+
+impl Jump {
+    fn compile(&self) -> u16 {
+        match self {
+            Jump::NONE => 0b000,
+            Jump::JGT => 0b001,
+            Jump::JEQ => 0b010,
+            Jump::JGE => 0b011,
+            Jump::JLT => 0b100,
+            Jump::JNE => 0b101,
+            Jump::JLE => 0b110,
+            Jump::JMP => 0b111,
+        }
+    }
+
+    fn decode(word: u16) -> Self {
+        match word & 0b111 {
+            0b000 => Jump::NONE,
+            0b001 => Jump::JGT,
+            0b010 => Jump::JEQ,
+            0b011 => Jump::JGE,
+            0b100 => Jump::JLT,
+            0b101 => Jump::JNE,
+            0b110 => Jump::JLE,
+            _ => Jump::JMP,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Compute {
+    Zero,
+    One,
+    NegOne,
+    D,
+    A,
+    NotD,
+    NotA,
+    NegD,
+    NegA,
+    DplusOne,
+    AplusOne,
+    DminOne,
+    AminOne,
+    DplusA,
+    DminA,
+    AminD,
+    DandA,
+    DorA,
+    M,
+    NotM,
+    NegM,
+    MplusOne,
+    MminOne,
+    DplusM,
+    DminM,
+    MminD,
+    DandM,
+    DorM,
+}
+
+impl TryFrom<&str> for Compute {
+    type Error = Diagnostic;
+
+    /// Accepts the spellings of the book as well as whitespace and swapped operands of
+    /// commutative operators, such as `M+D` for `D+M`.
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let value: String = value.split_whitespace().collect();
+        Compute::from_spelling(&value)
+            .or_else(|| {
+                let (left, op, right) = value
+                    .find(['+', '&', '|'])
+                    .map(|i| (&value[..i], &value[i..i + 1], &value[i + 1..]))?;
+                Compute::from_spelling(&format!("{}{}{}", right, op, left))
+            })
+            .ok_or_else(|| {
+                let spellings: Vec<String> = Compute::ALL.iter().map(|c| c.to_string()).collect();
+                let mut message = format!("Unknown compute command '{}'", value);
+                if let Some(suggestion) = suggest(&value, spellings.iter().map(|s| s.as_str())) {
+                    message = format!("{}, {}", message, suggestion);
+                }
+                Diagnostic::error("unknown-compute", message)
+            })
+    }
+}
+
+impl Compute {
+    fn from_spelling(value: &str) -> Option<Self> {
+        match value {
+            "0" => Some(Compute::Zero),
+            "1" => Some(Compute::One),
+            "-1" => Some(Compute::NegOne),
+            "D" => Some(Compute::D),
+            "A" => Some(Compute::A),
+            "!D" => Some(Compute::NotD),
+            "!A" => Some(Compute::NotA),
+            "-D" => Some(Compute::NegD),
+            "-A" => Some(Compute::NegA),
+            "D+1" => Some(Compute::DplusOne),
+            "A+1" => Some(Compute::AplusOne),
+            "D-1" => Some(Compute::DminOne),
+            "A-1" => Some(Compute::AminOne),
+            "D+A" => Some(Compute::DplusA),
+            "D-A" => Some(Compute::DminA),
+            "A-D" => Some(Compute::AminD),
+            "D&A" => Some(Compute::DandA),
+            "D|A" => Some(Compute::DorA),
+            "M" => Some(Compute::M),
+            "!M" => Some(Compute::NotM),
+            "-M" => Some(Compute::NegM),
+            "M+1" => Some(Compute::MplusOne),
+            "M-1" => Some(Compute::MminOne),
+            "D+M" => Some(Compute::DplusM),
+            "D-M" => Some(Compute::DminM),
+            "M-D" => Some(Compute::MminD),
+            "D&M" => Some(Compute::DandM),
+            "D|M" => Some(Compute::DorM),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Compute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Compute::Zero => "0",
+            Compute::One => "1",
+            Compute::NegOne => "-1",
+            Compute::D => "D",
+            Compute::A => "A",
+            Compute::NotD => "!D",
+            Compute::NotA => "!A",
+            Compute::NegD => "-D",
+            Compute::NegA => "-A",
+            Compute::DplusOne => "D+1",
+            Compute::AplusOne => "A+1",
+            Compute::DminOne => "D-1",
+            Compute::AminOne => "A-1",
+            Compute::DplusA => "D+A",
+            Compute::DminA => "D-A",
+            Compute::AminD => "A-D",
+            Compute::DandA => "D&A",
+            Compute::DorA => "D|A",
+            Compute::M => "M",
+            Compute::NotM => "!M",
+            Compute::NegM => "-M",
+            Compute::MplusOne => "M+1",
+            Compute::MminOne => "M-1",
+            Compute::DplusM => "D+M",
+            Compute::DminM => "D-M",
+            Compute::MminD => "M-D",
+            Compute::DandM => "D&M",
+            Compute::DorM => "D|M",
+        })
+    }
+}
+
+impl Compute {
+    const ALL: [Compute; 28] = [
+        Compute::Zero,
+        Compute::One,
+        Compute::NegOne,
+        Compute::D,
+        Compute::A,
+        Compute::NotD,
+        Compute::NotA,
+        Compute::NegD,
+        Compute::NegA,
+        Compute::DplusOne,
+        Compute::AplusOne,
+        Compute::DminOne,
+        Compute::AminOne,
+        Compute::DplusA,
+        Compute::DminA,
+        Compute::AminD,
+        Compute::DandA,
+        Compute::DorA,
+        Compute::M,
+        Compute::NotM,
+        Compute::NegM,
+        Compute::MplusOne,
+        Compute::MminOne,
+        Compute::DplusM,
+        Compute::DminM,
+        Compute::MminD,
+        Compute::DandM,
+        Compute::DorM,
+    ];
+
+    /// The registers the computation reads.
+    fn reads(&self) -> Target {
+        self.to_string()
+            .chars()
+            .fold(Target::empty(), |reads, c| match c {
+                'A' => reads | Target::A,
+                'D' => reads | Target::D,
+                'M' => reads | Target::M,
+                _ => reads,
+            })
+    }
+
+    /// The computation encoded in the `a` and `c` bits of a C-instruction, if it is one of
+    /// the 28 defined ones.
+    fn decode(word: u16) -> Option<Self> {
+        const MASK: u16 = 0b1111111 << 6;
+        Self::ALL.into_iter().find(|c| c.compile() == word & MASK)
+    }
+
+    fn compile(&self) -> u16 {
+        let out = match self {
+            Compute::Zero => 0b101010,
+            Compute::One => 0b111111,
+            Compute::NegOne => 0b111010,
+            Compute::D => 0b001100,
+            Compute::A | Compute::M => 0b110000,
+            Compute::NotD => 0b001101,
+            Compute::NotA | Compute::NotM => 0b110001,
+            Compute::NegD => 0b001111,
+            Compute::NegA | Compute::NegM => 0b110011,
+            Compute::DplusOne => 0b011111,
+            Compute::AplusOne | Compute::MplusOne => 0b110111,
+            Compute::DminOne => 0b001110,
+            Compute::AminOne | Compute::MminOne => 0b110010,
+            Compute::DplusA | Compute::DplusM => 0b000010,
+            Compute::DminA | Compute::DminM => 0b010011,
+            Compute::AminD | Compute::MminD => 0b000111,
+            Compute::DandA | Compute::DandM => 0b000000,
+            Compute::DorA | Compute::DorM => 0b010101,
+        };
+        let mem: u16 = match self {
+            Compute::M
+            | Compute::NotM
+            | Compute::NegM
+            | Compute::MplusOne
+            | Compute::MminOne
+            | &Compute::DplusM
+            | Compute::DminM
+            | Compute::MminD
+            | Compute::DandM
+            | Compute::DorM => 1,
+            _ => 0,
+        };
+        ((mem << 6) | out) << 6
+    }
+}
+
+type Label = String;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadData {
+    Data(u16),
+    Label(Label),
+    Expression(Expression),
+}
+
+impl Display for LoadData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadData::Data(data) => write!(f, "{}", data),
+            LoadData::Label(label) => f.write_str(label),
+            LoadData::Expression(expression) => write!(f, "{}", expression),
+        }
+    }
+}
+
+impl LoadData {
+    pub fn label(str: &str) -> LoadData {
+        LoadData::Label(str.to_string())
+    }
+
+    /// Every symbol the value refers to.
+    fn symbols(&self) -> Vec<&str> {
+        match self {
+            LoadData::Data(_) => Vec::new(),
+            LoadData::Label(label) => vec![label.as_str()],
+            LoadData::Expression(expression) => expression.symbols(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Instruction {
+    Label {
+        label: Label,
+    },
+    Load {
+        data: LoadData,
+    },
+    Command {
+        compute: Compute,
+        target: Target,
+        jump: Jump,
+    },
+}
+
+/// Writes the instruction in canonical `dest=comp;jump` form.
+impl Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Instruction::Label { label } => write!(f, "({})", label),
+            Instruction::Load { data } => write!(f, "@{}", data),
+            Instruction::Command {
+                compute,
+                target,
+                jump,
+            } => {
+                if !target.is_empty() {
+                    write!(f, "{}=", target)?;
+                }
+                write!(f, "{}", compute)?;
+                if !matches!(jump, Jump::NONE) {
+                    write!(f, ";{}", jump)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Instruction {
+    pub fn label(str: &str) -> Instruction {
+        Instruction::Label {
+            label: str.to_string(),
+        }
+    }
+
+    /// Decodes a single word of machine code. Returns `None` for C-instructions whose
+    /// computation is undefined or whose two unused bits are not set.
+    pub fn decode(word: u16) -> Option<Self> {
+        if word & 0x8000 == 0 {
+            return Some(Instruction::Load {
+                data: LoadData::Data(word),
+            });
+        }
+        if word & 0xE000 != 0xE000 {
+            return None;
+        }
+        Some(Instruction::Command {
+            compute: Compute::decode(word)?,
+            target: Target::decode(word),
+            jump: Jump::decode(word),
+        })
+    }
+
+    /// Pushes D onto the VM stack.
+    pub fn push_d() -> Vec<Instruction> {
+        [
+            Instruction::Load {
+                data: LoadData::label("SP"),
+            },
+            Instruction::Command {
+                compute: Compute::M,
+                target: Target::A,
+                jump: Jump::NONE,
+            },
+            Instruction::Command {
+                compute: Compute::D,
+                target: Target::M,
+                jump: Jump::NONE,
+            },
+            Instruction::Load {
+                data: LoadData::label("SP"),
+            },
+            Instruction::Command {
+                compute: Compute::MplusOne,
+                target: Target::M,
+                jump: Jump::NONE,
+            },
+        ]
+        .to_vec()
+    }
+
+    /// Pops the top of the VM stack into the target.
+    pub fn pop(target: Target) -> Vec<Instruction> {
+        [
+            Instruction::Load {
+                data: LoadData::label("SP"),
+            },
+            Instruction::Command {
+                compute: Compute::MminOne,
+                target: Target::A | Target::M,
+                jump: Jump::NONE,
+            },
+            Instruction::Command {
+                compute: Compute::M,
+                target,
+                jump: Jump::NONE,
+            },
+        ]
+        .to_vec()
+    }
+
+    /// Loads the value of an expression into A, folding it into the simplest form.
+    pub fn load(expression: Expression) -> Vec<Instruction> {
+        match expression {
+            Expression::Literal(value) => Instruction::load_a(value as u16),
+            Expression::Symbol(label) => vec![Instruction::Load {
+                data: LoadData::Label(label),
+            }],
+            expression => vec![Instruction::Load {
+                data: LoadData::Expression(expression),
+            }],
+        }
+    }
+
+    /// The shortest sequence loading any 16-bit value into A.
+    pub fn load_a(value: u16) -> Vec<Instruction> {
+        let command = |compute| Instruction::Command {
+            compute,
+            target: Target::A,
+            jump: Jump::NONE,
+        };
+        match value {
+            0..=MAX_LOAD => vec![Instruction::Load {
+                data: LoadData::Data(value),
+            }],
+            0xFFFF => vec![command(Compute::NegOne)],
+            _ => vec![
+                Instruction::Load {
+                    data: LoadData::Data(!value),
+                },
+                command(Compute::NotA),
+            ],
+        }
+    }
+
+    /// The shortest sequence loading any 16-bit value into D. Also overwrites A unless the
+    /// value is 0, 1 or -1.
+    pub fn load_d(value: u16) -> Vec<Instruction> {
+        let command = |compute| Instruction::Command {
+            compute,
+            target: Target::D,
+            jump: Jump::NONE,
+        };
+        match value {
+            0 => vec![command(Compute::Zero)],
+            1 => vec![command(Compute::One)],
+            0xFFFF => vec![command(Compute::NegOne)],
+            2..=MAX_LOAD => vec![
+                Instruction::Load {
+                    data: LoadData::Data(value),
+                },
+                command(Compute::A),
+            ],
+            _ => vec![
+                Instruction::Load {
+                    data: LoadData::Data(!value),
+                },
+                command(Compute::NotA),
+            ],
+        }
+    }
+
+    fn compile(&self, ls: &mut LabelStore) -> Result<Option<u16>, Diagnostic> {
+        match self {
+            Instruction::Label { label: _ } => Ok(None),
+            Instruction::Load { data: ld } => {
+                let value = match ld {
+                    LoadData::Data(data) => i64::from(*data),
+                    LoadData::Label(label) => i64::from(ls.get(label)),
+                    LoadData::Expression(expression) => expression.evaluate(ls)?,
+                };
+                if !(0..=i64::from(MAX_LOAD)).contains(&value) {
+                    return Err(Diagnostic::error(
+                        "value-too-large",
+                        format!(
+                            "Value {} of '{}' does not fit in an A-instruction, the maximum is {}",
+                            value, ld, MAX_LOAD
+                        ),
+                    ));
+                }
+                Ok(Some(value as u16))
+            }
+            Instruction::Command {
+                compute,
+                target,
+                jump,
+            } => Ok(Some(
+                0xE000 | compute.compile() | target.compile() | jump.compile(),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Assembly {
+    instructions: Vec<Instruction>,
+    /// The source location each instruction was created from
+    locations: Vec<Option<Location>>,
+    /// Labels other objects may refer to, besides the entry points of VM functions
+    exports: Vec<(Label, Option<Location>)>,
+}
+
+impl Assembly {
+    pub fn from_file(path: &Path) -> Result<Self, Vec<Diagnostic>> {
+        let mut stringbuf = String::new();
+        File::open(path)
+            .map_err(|e| Diagnostic::io(path, e))?
+            .read_to_string(&mut stringbuf)
+            .map_err(|e| Diagnostic::io(path, e))?;
+        Self::from_source(&stringbuf, path)
+    }
+
+    /// Parses assembly source, with `path` naming the file for diagnostics and includes.
+    pub fn from_source(src: &str, path: &Path) -> Result<Self, Vec<Diagnostic>> {
+        let file: Rc<str> = path.to_string_lossy().into();
+
+        let expanded = preprocessor::expand(src, path)?;
+        let (out, errs) = parser::items().parse(&expanded.text).into_output_errors();
+        if !errs.is_empty() {
+            return Err(errs
+                .into_iter()
+                .map(|e| expanded.remap(Diagnostic::parse(e, &file)))
+                .collect());
+        }
+
+        // Constants may be used before they are defined, so they are all collected first
+        let items = out.unwrap_or_default();
+        let mut constants = HashMap::new();
+        let mut definitions: HashMap<String, Location> = HashMap::new();
+        let mut errors = Vec::new();
+        for (item, span) in &items {
+            let Item::Constant(name, value) = item else {
+                continue;
+            };
+            let location = expanded.location(span.into_range());
+            if let Some(first) = definitions.get(name) {
+                errors.push(
+                    Diagnostic::error(
+                        "duplicate-constant",
+                        format!("Constant '{}' already exists", name),
+                    )
+                    .with_location(location)
+                    .with_label("defined again here")
+                    .with_secondary(first.clone(), "first defined here"),
+                );
+                continue;
+            }
+            constants.insert(name.clone(), value.clone());
+            definitions.insert(name.clone(), location);
+        }
+        errors.extend(resolve_constants(&mut constants, &definitions));
+
+        let mut assembly = Assembly::default();
+        for (item, span) in items {
+            let location = expanded.location(span.into_range());
+            match item {
+                Item::Constant(_, _) => (),
+                Item::Export(name) => assembly.exports.push((name, Some(location))),
+                Item::Instructions(instructions) => {
+                    for instruction in instructions {
+                        let instructions = match instruction {
+                            Instruction::Load {
+                                data: LoadData::Label(label),
+                            } => {
+                                Instruction::load(Expression::Symbol(label).substitute(&constants))
+                            }
+                            Instruction::Load {
+                                data: LoadData::Expression(expression),
+                            } => Instruction::load(expression.substitute(&constants)),
+                            Instruction::Label { label } if definitions.contains_key(&label) => {
+                                errors.push(
+                                    Diagnostic::error(
+                                        "duplicate-label",
+                                        format!("Label '{}' has the name of a constant", label),
+                                    )
+                                    .with_location(location.clone())
+                                    .with_secondary(
+                                        definitions[&label].clone(),
+                                        "constant defined here",
+                                    ),
+                                );
+                                continue;
+                            }
+                            instruction => vec![instruction],
+                        };
+                        assembly.extend(instructions, Some(&location));
+                    }
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(assembly)
+    }
+
+    pub fn from_instructions(instructions: Vec<Instruction>) -> Self {
+        let locations = vec![None; instructions.len()];
+        Self {
+            instructions,
+            locations,
+            exports: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, instruction: Instruction, location: Option<Location>) {
+        self.instructions.push(instruction);
+        self.locations.push(location);
+    }
+
+    /// Appends instructions that all stem from the same source location.
+    pub fn extend(&mut self, instructions: Vec<Instruction>, location: Option<&Location>) {
+        for instruction in instructions {
+            self.push(instruction, location.cloned());
+        }
+    }
+
+    pub fn append(&mut self, other: &mut Assembly) {
+        self.instructions.append(&mut other.instructions);
+        self.locations.append(&mut other.locations);
+        self.exports.append(&mut other.exports);
+    }
+
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        let mut file = File::create(path)?;
+        for instr in &self.instructions {
+            match instr {
+                Instruction::Label { label: _ } => writeln!(file, "{}", instr)?,
+                _ => writeln!(file, "    {}", instr)?,
+            }
+        }
+        file.flush()
+    }
+
+    /// Number of words the program takes in ROM.
+    pub fn size(&self) -> usize {
+        self.instructions
+            .iter()
+            .filter(|i| !matches!(i, Instruction::Label { label: _ }))
+            .count()
+    }
+
+    /// Assigns every label its ROM address.
+    fn labels(&self) -> Result<LabelStore, Vec<Diagnostic>> {
+        let mut ls = LabelStore::new();
+        let mut definitions: HashMap<&str, &Option<Location>> = HashMap::new();
+        let mut errors = Vec::new();
+
+        let mut ic: usize = 0;
+        for (instruction, location) in self.instructions.iter().zip(&self.locations) {
+            match instruction {
+                Instruction::Label { label } => {
+                    if let Err(e) = ls.insert(label, ic as u16) {
+                        let mut e = e.or_location(location.as_ref());
+                        if let Some(Some(first)) = definitions.get(label.as_str()) {
+                            e = e.with_secondary(first.clone(), "first defined here");
+                        }
+                        errors.push(e);
+                    }
+                    definitions.insert(label, location);
+                }
+                Instruction::Load { data: _ }
+                | Instruction::Command {
+                    compute: _,
+                    target: _,
+                    jump: _,
+                } => {
+                    if ic == ROM_SIZE {
+                        errors.push(
+                            rom_overflow(self.size())
+                                .or_location(location.as_ref())
+                                .with_label("the first instruction that does not fit"),
+                        );
+                    }
+                    ic += 1;
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(ls)
+    }
+
+    /// Resolves all symbols and encodes every instruction. Label definitions take no space
+    /// in ROM and are encoded as `None`.
+    fn assemble(&self) -> Result<(Vec<Option<u16>>, LabelStore), Vec<Diagnostic>> {
+        let mut ls = self.labels()?;
+        let mut words = Vec::new();
+        let mut errors = Vec::new();
+        for (instruction, location) in self.instructions.iter().zip(&self.locations) {
+            match instruction.compile(&mut ls) {
+                Ok(word) => words.push(word),
+                Err(e) => errors.push(e.or_location(location.as_ref())),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok((words, ls))
+    }
+
+    /// The symbol table the program is assembled with.
+    pub fn symbols(&self) -> Result<SymbolTable, Vec<Diagnostic>> {
+        let (_, ls) = self.assemble()?;
+        Ok(ls.table())
+    }
+
+    /// How the assembled program uses ROM and RAM.
+    pub fn memory_map(&self) -> Result<MemoryMap, Vec<Diagnostic>> {
+        let (_, ls) = self.assemble()?;
+        Ok(MemoryMap::new(self.size(), ls.table()))
+    }
+
+    /// Encodes the program without fixing where it ends up in ROM. Loads of anything but
+    /// predefined symbols and literals are left to the linker as relocations.
+    pub fn object(&self) -> Result<Object, Vec<Diagnostic>> {
+        let mut ls = self.labels()?;
+        let mut errors = Vec::new();
+
+        let mut locals = Vec::new();
+        let mut exports = Vec::new();
+        for (instruction, location) in self.instructions.iter().zip(&self.locations) {
+            let Instruction::Label { label } = instruction else {
+                continue;
+            };
+            let address = ls.lookup(label).unwrap_or_default();
+            let exported = self.exports.iter().find(|(name, _)| name == label);
+            match (SymbolKind::label(label), exported) {
+                (SymbolKind::Function, _) => exports.push(Export {
+                    name: label.clone(),
+                    address,
+                    location: location.clone(),
+                }),
+                (_, Some((_, export))) => exports.push(Export {
+                    name: label.clone(),
+                    address,
+                    location: export.clone().or(location.clone()),
+                }),
+                _ => locals.push((label.clone(), address)),
+            }
+        }
+        for (name, location) in &self.exports {
+            if !exports.iter().any(|e| e.name == *name) {
+                errors.push(
+                    Diagnostic::error(
+                        "unknown-export",
+                        format!("Exported label '{}' is not defined", name),
+                    )
+                    .or_location(location.as_ref()),
+                );
+            }
+        }
+
+        let mut code = Vec::new();
+        let mut relocations = Vec::new();
+        let mut imports: Vec<String> = Vec::new();
+        let predefined = |name: &&str| PREDEFINED.iter().any(|(p, _)| p == name);
+        for (instruction, location) in self.instructions.iter().zip(&self.locations) {
+            let symbols = match instruction {
+                Instruction::Load { data } => data.symbols(),
+                _ => Vec::new(),
+            };
+            if symbols.iter().all(predefined) {
+                match instruction.compile(&mut ls) {
+                    Ok(Some(word)) => code.push(word),
+                    Ok(None) => (),
+                    Err(e) => errors.push(e.or_location(location.as_ref())),
+                }
+                continue;
+            }
+            for symbol in symbols.into_iter().filter(|s| !predefined(s)) {
+                if ls.lookup(symbol).is_none() && !imports.iter().any(|i| i == symbol) {
+                    imports.push(symbol.to_string());
+                }
+            }
+            if let Instruction::Load { data } = instruction {
+                relocations.push(Relocation {
+                    address: code.len() as u16,
+                    data: data.clone(),
+                    location: location.clone(),
+                });
+                code.push(0);
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Object {
+            code,
+            exports,
+            locals,
+            imports,
+            relocations,
+        })
+    }
+
+    pub fn compile(self) -> Result<CodeType, Vec<Diagnostic>> {
+        Ok(CodeType::Object(self.object()?))
+    }
+}
+
+/// The error for a program that does not fit in ROM.
+/// Substitutes the constants into each other until none refers to another. A constant still
+/// referring to one after as many rounds as there are constants is part of a cycle.
+fn resolve_constants(
+    constants: &mut HashMap<String, Expression>,
+    definitions: &HashMap<String, Location>,
+) -> Vec<Diagnostic> {
+    let unresolved = |constants: &HashMap<String, Expression>| -> Vec<String> {
+        constants
+            .iter()
+            .filter(|(_, value)| value.symbols().iter().any(|s| constants.contains_key(*s)))
+            .map(|(name, _)| name.clone())
+            .collect()
+    };
+    for _ in 0..constants.len() {
+        if unresolved(constants).is_empty() {
+            break;
+        }
+        let previous = constants.clone();
+        for value in constants.values_mut() {
+            *value = value.clone().substitute(&previous);
+        }
+    }
+    let mut cyclic = unresolved(constants);
+    cyclic.sort_by_key(|name| definitions[name].span.start);
+    cyclic
+        .into_iter()
+        .map(|name| {
+            Diagnostic::error(
+                "constant-cycle",
+                format!("Constant '{}' is defined in terms of itself", name),
+            )
+            .with_location(definitions[&name].clone())
+        })
+        .collect()
+}
+
+fn rom_overflow(size: usize) -> Diagnostic {
+    Diagnostic::error(
+        "rom-overflow",
+        format!(
+            "The program takes {} words, but ROM only holds {}",
+            size, ROM_SIZE
+        ),
+    )
+}
+
+/// Symbols every program can use without defining them.
+const PREDEFINED: [(&str, u16); 23] = [
+    ("SP", 0),
+    ("LCL", 1),
+    ("ARG", 2),
+    ("THIS", 3),
+    ("THAT", 4),
+    ("R0", 0),
+    ("R1", 1),
+    ("R2", 2),
+    ("R3", 3),
+    ("R4", 4),
+    ("R5", 5),
+    ("R6", 6),
+    ("R7", 7),
+    ("R8", 8),
+    ("R9", 9),
+    ("R10", 10),
+    ("R11", 11),
+    ("R12", 12),
+    ("R13", 13),
+    ("R14", 14),
+    ("R15", 15),
+    ("SCREEN", 0x4000),
+    ("KBD", 0x6000),
+];
+
+#[derive(Debug)]
+struct LabelStore {
+    labels: HashMap<String, (u16, SymbolKind)>,
+    nextindex: u16,
+}
+
+impl LabelStore {
+    fn new() -> LabelStore {
+        let hm = PREDEFINED
+            .into_iter()
+            .map(|(e1, e2)| (e1.to_string(), (e2, SymbolKind::Predefined)))
+            .collect();
+        LabelStore {
+            labels: hm,
+            nextindex: 16,
+        }
+    }
+
+    fn get(&mut self, key: &str) -> u16 {
+        self.labels
+            .entry(key.to_string())
+            .or_insert_with(|| {
+                let r = self.nextindex;
+                self.nextindex += 1;
+                (r, SymbolKind::variable(key))
+            })
+            .0
+    }
+
+    /// The value of a symbol without allocating it.
+    fn lookup(&self, key: &str) -> Option<u16> {
+        self.labels.get(key).map(|(value, _)| *value)
+    }
+
+    fn insert(&mut self, key: &str, value: u16) -> Result<(), Diagnostic> {
+        if self.labels.contains_key(key) {
+            return Err(Diagnostic::error(
+                "duplicate-label",
+                format!("Label '{}' already exists", key),
+            )
+            .with_label("defined again here"));
+        }
+        self.labels
+            .insert(key.to_string(), (value, SymbolKind::label(key)));
+        Ok(())
+    }
+
+    /// Defines a symbol for a while, returning whatever it hides so it can be restored.
+    fn shadow(&mut self, key: &str, value: u16) -> Option<(u16, SymbolKind)> {
+        self.labels
+            .insert(key.to_string(), (value, SymbolKind::label(key)))
+    }
+
+    fn restore(&mut self, key: &str, previous: Option<(u16, SymbolKind)>) {
+        match previous {
+            Some(previous) => self.labels.insert(key.to_string(), previous),
+            None => self.labels.remove(key),
+        };
+    }
+
+    /// All symbols of one kind, ordered by value and then by name.
+    fn symbols(&self, kind: SymbolKind) -> Vec<(&str, u16)> {
+        let mut symbols: Vec<_> = self
+            .labels
+            .iter()
+            .filter(|(_, (_, k))| *k == kind)
+            .map(|(name, (value, _))| (name.as_str(), *value))
+            .collect();
+        symbols.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(b.0)));
+        symbols
+    }
+
+    /// Every symbol the program defined or allocated, leaving out the predefined ones.
+    fn table(&self) -> SymbolTable {
+        let kinds = [
+            SymbolKind::Label,
+            SymbolKind::Function,
+            SymbolKind::Variable,
+            SymbolKind::Static,
+        ];
+        let symbols = kinds
+            .into_iter()
+            .flat_map(|kind| {
+                self.symbols(kind)
+                    .into_iter()
+                    .map(move |(name, value)| Symbol {
+                        name: name.to_string(),
+                        value,
+                        kind,
+                    })
+            })
+            .collect();
+        SymbolTable { symbols }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{cpu::Cpu, diagnostic::Diagnostic, hex::Hex};
+
+    use super::{Assembly, Compute, Jump};
+
+    fn assemble(src: &str) -> Result<Assembly, Vec<Diagnostic>> {
+        Assembly::from_source(src, Path::new("test.asm"))
+    }
+
+    /// Runs every instruction of the program once, returning the size of the program and the
+    /// CPU afterwards.
+    fn run(src: &str) -> (usize, Cpu) {
+        let (words, _) = assemble(src).unwrap().assemble().unwrap();
+        let instructions: Vec<u16> = words.into_iter().flatten().collect();
+        let size = instructions.len();
+        let mut cpu = Cpu::new(&Hex { instructions });
+        cpu.run(size as u64);
+        (size, cpu)
+    }
+
+    fn load(src: &str) -> (usize, u16) {
+        let (size, cpu) = run(src);
+        (size, cpu.a)
+    }
+
+    fn errors(src: &str) -> Vec<&'static str> {
+        assemble(src).unwrap_err().iter().map(|e| e.code).collect()
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(load("@42"), (1, 42));
+        assert_eq!(load("@0x7FFF"), (1, 0x7FFF));
+        assert_eq!(load("@0b1010"), (1, 10));
+        assert_eq!(load("@'A'"), (1, 65));
+        assert_eq!(load("@-1"), (1, 0xFFFF));
+        assert_eq!(load("@0xFFFF"), (1, 0xFFFF));
+        assert_eq!(load("@-2"), (2, 0xFFFE));
+        assert_eq!(load("@32768"), (2, 0x8000));
+        assert_eq!(load("@-32768"), (2, 0x8000));
+    }
+
+    #[test]
+    fn load_register() {
+        for (src, size, value) in [
+            ("D=#0", 1, 0),
+            ("D=#1", 1, 1),
+            ("D=#-1", 1, 0xFFFF),
+            ("D=#1000", 2, 1000),
+            ("D=#0x8000", 2, 0x8000),
+            ("D=#-5", 2, 0xFFFB),
+        ] {
+            let (actual, cpu) = run(src);
+            assert_eq!((actual, cpu.d), (size, value), "{}", src);
+        }
+        assert_eq!(load("A=#0xABCD"), (2, 0xABCD));
+    }
+
+    #[test]
+    fn literals_out_of_range() {
+        assert_eq!(errors("@65536"), ["parse"]);
+        assert_eq!(errors("@-32769"), ["parse"]);
+        assert_eq!(errors("@0x10000"), ["parse"]);
+        assert_eq!(errors("D=#70000"), ["parse"]);
+        // Every bad line is reported
+        assert_eq!(errors("@65536\n@0b10000000000000000\n"), ["parse", "parse"]);
+    }
+
+    /// The words a program assembles to.
+    fn words(src: &str) -> Vec<u16> {
+        let (words, _) = assemble(src).unwrap().assemble().unwrap();
+        words.into_iter().flatten().collect()
+    }
+
+    fn message(src: &str) -> String {
+        assemble(src).unwrap_err()[0].message.clone()
+    }
+
+    #[test]
+    fn commutative_spellings() {
+        for (spelling, canonical) in [
+            ("M+D", Compute::DplusM),
+            ("A+D", Compute::DplusA),
+            ("1+D", Compute::DplusOne),
+            ("1+M", Compute::MplusOne),
+            ("M&D", Compute::DandM),
+            ("A|D", Compute::DorA),
+            ("D + M", Compute::DplusM),
+            (" M - D ", Compute::MminD),
+        ] {
+            let compute = Compute::try_from(spelling).unwrap();
+            assert_eq!(compute.to_string(), canonical.to_string(), "{}", spelling);
+        }
+        // Swapping the operands of a subtraction changes its result
+        assert!(Compute::try_from("1-D").is_err());
+        assert!(Compute::try_from("M-A").is_err());
+    }
+
+    #[test]
+    fn spaced_commands() {
+        assert_eq!(words("D = M + D ; JGT\n"), words("D=D+M;JGT\n"));
+        assert_eq!(words("AM=M&D\n"), words("AM=D&M\n"));
+        assert_eq!(words("MD=D+1\n"), words("DM=1+D\n"));
+    }
+
+    #[test]
+    fn suggestions() {
+        assert_eq!(
+            message("D=D+N\n"),
+            "Unknown compute command 'D+N', did you mean D+1, D+A or D+M?"
+        );
+        assert_eq!(
+            message("0;JMPP\n"),
+            "Unknown jump condition 'JMPP', did you mean JMP?"
+        );
+        assert!(Jump::try_from("XYZW").is_err());
+        assert_eq!(errors("X=D\n"), ["parse"]);
+    }
+
+    #[test]
+    fn constants() {
+        assert_eq!(load(".equ FOO 5\n@FOO"), (1, 5));
+        assert_eq!(load(".define SIZE 4\n.equ END SIZE*2+1\n@END"), (1, 9));
+        assert_eq!(load(".equ BASE 0x4000\n@BASE+32"), (1, 0x4020));
+        assert_eq!(
+            errors(".equ FOO 1\n.define FOO 2\n"),
+            ["duplicate-constant"]
+        );
+        assert_eq!(errors(".equ LOOP 1\n(LOOP)\n"), ["duplicate-label"]);
+    }
+
+    #[test]
+    fn constants_before_definition() {
+        assert_eq!(
+            words("@FOO\nD=A\n.equ FOO 5\n@FOO\n"),
+            words("@5\nD=A\n@5\n")
+        );
+        assert_eq!(load("@END\n.equ END SIZE+1\n.define SIZE 4\n"), (1, 5));
+        assert_eq!(
+            errors(".equ A B+1\n.equ B A\n.equ C A\n.equ D 1\n"),
+            ["constant-cycle", "constant-cycle", "constant-cycle"]
+        );
+    }
+
+    #[test]
+    fn macros() {
+        let (_, cpu) = run(
+            ".macro SET dest, value\n  @value\n  D=A\n  @dest\n  M=D\n.endm\n\
+                            SET 20, 7\nSET 21 8\n",
+        );
+        assert_eq!((cpu.ram[20], cpu.ram[21]), (7, 8));
+        // Each expansion gets its own labels
+        assert!(assemble(".macro SKIP\n  @end\\@\n  0;JMP\n(end\\@)\n.endm\nSKIP\nSKIP\n").is_ok());
+    }
+
+    #[test]
+    fn builtin_macros() {
+        let (_, cpu) = run("@256\nD=A\n@SP\nM=D\n\
+                            @7\nD=A\nPUSH_D\n@9\nD=A\nPUSH_D\nPOP_D\nPOP_A\n");
+        assert_eq!((cpu.d, cpu.a, cpu.ram[0]), (9, 7, 256));
+        assert_eq!((cpu.ram[256], cpu.ram[257]), (7, 9));
+    }
+}
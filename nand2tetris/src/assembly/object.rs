@@ -0,0 +1,159 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+    rc::Rc,
+};
+
+use chumsky::{Parser, prelude::end};
+
+use crate::diagnostic::{Diagnostic, Location};
+
+use super::{Expression, LoadData, parser};
+
+/// A label other objects can refer to.
+#[derive(Debug, Clone)]
+pub struct Export {
+    pub name: String,
+    /// Address relative to the start of the object
+    pub address: u16,
+    pub location: Option<Location>,
+}
+
+/// A word of code that loads the address of a symbol, filled in by the linker.
+#[derive(Debug, Clone)]
+pub struct Relocation {
+    /// Address of the word relative to the start of the object
+    pub address: u16,
+    /// The label or expression to load
+    pub data: LoadData,
+    pub location: Option<Location>,
+}
+
+/// Assembled code that can still be placed anywhere in ROM. Stored in `.obj` files, which
+/// list exports, local labels and imports followed by one line per word of code. Words that
+/// need relocating are written as their A-instruction instead of in binary:
+///
+/// ```text
+/// export function:Main.main 0
+/// local LOOP 4
+/// import counter
+/// 0000000000000000
+/// @counter
+/// ```
+#[derive(Debug, Default)]
+pub struct Object {
+    /// The encoded program, with a placeholder at every relocation
+    pub code: Vec<u16>,
+    pub exports: Vec<Export>,
+    /// Labels only this object can refer to, relative to its start
+    pub locals: Vec<(String, u16)>,
+    /// Symbols this object refers to but does not define, either exported by another object
+    /// or variables
+    pub imports: Vec<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl Object {
+    pub fn from_file(path: &Path) -> Result<Self, Vec<Diagnostic>> {
+        let mut src = String::new();
+        File::open(path)
+            .map_err(|e| Diagnostic::io(path, e))?
+            .read_to_string(&mut src)
+            .map_err(|e| Diagnostic::io(path, e))?;
+        let file: Rc<str> = path.to_string_lossy().into();
+
+        let mut object = Object::default();
+        let mut errors = Vec::new();
+        let mut offset = 0;
+        for line in src.split_inclusive('\n') {
+            let content = line.trim();
+            let start = offset + (line.len() - line.trim_start().len());
+            let location = Location::new(&file, start..start + content.len());
+            offset += line.len();
+            if content.is_empty() || content.starts_with("//") {
+                continue;
+            }
+            if let Err(e) = object.read_line(content, &location) {
+                errors.push(e.with_location(location));
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(object)
+    }
+
+    fn read_line(&mut self, content: &str, location: &Location) -> Result<(), Diagnostic> {
+        let address = |value: &str| {
+            value.parse().map_err(|_| {
+                Diagnostic::error("invalid-object", format!("Invalid address '{}'", value))
+            })
+        };
+        let mut parts = content.split_whitespace();
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some("export"), Some(name), Some(value), None) => self.exports.push(Export {
+                name: name.to_string(),
+                address: address(value)?,
+                location: Some(location.clone()),
+            }),
+            (Some("local"), Some(name), Some(value), None) => {
+                self.locals.push((name.to_string(), address(value)?))
+            }
+            (Some("import"), Some(name), None, None) => self.imports.push(name.to_string()),
+            (Some(word), None, None, None) if word.starts_with('@') => {
+                let expression = parser::expression()
+                    .then_ignore(end())
+                    .parse(&word[1..])
+                    .into_result()
+                    .map_err(|_| {
+                        Diagnostic::error("invalid-object", "Invalid relocation")
+                            .with_label("expected `@` followed by a symbol or expression")
+                    })?;
+                let data = match expression {
+                    Expression::Symbol(label) => LoadData::Label(label),
+                    expression => LoadData::Expression(expression),
+                };
+                self.relocations.push(Relocation {
+                    address: self.code.len() as u16,
+                    data,
+                    location: Some(location.clone()),
+                });
+                self.code.push(0);
+            }
+            (Some(word), None, None, None) if word.len() == 16 => {
+                let word = u16::from_str_radix(word, 2).map_err(|_| {
+                    Diagnostic::error("invalid-object", "Invalid instruction")
+                        .with_label("expected exactly 16 binary digits")
+                })?;
+                self.code.push(word);
+            }
+            _ => {
+                return Err(Diagnostic::error("invalid-object", "Invalid object line")
+                    .with_label("expected an export, local, import or word of code"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        let mut file = File::create(path)?;
+        for export in &self.exports {
+            writeln!(file, "export {} {}", export.name, export.address)?;
+        }
+        for (name, address) in &self.locals {
+            writeln!(file, "local {} {}", name, address)?;
+        }
+        for import in &self.imports {
+            writeln!(file, "import {}", import)?;
+        }
+        let mut relocations = self.relocations.iter().peekable();
+        for (address, word) in self.code.iter().enumerate() {
+            match relocations.next_if(|r| r.address as usize == address) {
+                Some(relocation) => writeln!(file, "@{}", relocation.data)?,
+                None => writeln!(file, "{:0>16b}", word)?,
+            }
+        }
+        file.flush()
+    }
+}
@@ -0,0 +1,383 @@
+use std::{fmt::Display, mem::take};
+
+use super::{Assembly, Compute, Instruction, Jump, LoadData, Target};
+
+/// The rewrites of the peephole optimizer, in the order they are tried.
+#[derive(Debug, Clone, Copy)]
+enum Rule {
+    /// A push directly followed by a pop, which leaves the value where it already is
+    PushPop,
+    /// Loads of a value the register already holds
+    RedundantLoad,
+    /// Values put in D that are overwritten before anything reads them
+    DeadStore,
+    /// Jumps to the instruction that comes next anyway
+    JumpToNext,
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Rule::PushPop => "push/pop round-trips",
+            Rule::RedundantLoad => "redundant loads",
+            Rule::DeadStore => "dead stores to D",
+            Rule::JumpToNext => "jumps to the next instruction",
+        })
+    }
+}
+
+impl Rule {
+    const ALL: [Rule; 4] = [
+        Rule::PushPop,
+        Rule::RedundantLoad,
+        Rule::DeadStore,
+        Rule::JumpToNext,
+    ];
+
+    /// The indices of the instructions the rule removes, in ascending order.
+    fn apply(&self, code: &[Instruction]) -> Vec<usize> {
+        match self {
+            Rule::PushPop => push_pop(code),
+            Rule::RedundantLoad => redundant_load(code),
+            Rule::DeadStore => dead_store(code),
+            Rule::JumpToNext => jump_to_next(code),
+        }
+    }
+}
+
+/// How many instructions each rule saved.
+#[derive(Debug, Default)]
+pub struct Report {
+    before: usize,
+    saved: [usize; Rule::ALL.len()],
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let saved: usize = self.saved.iter().sum();
+        writeln!(
+            f,
+            "Optimized {} instructions down to {} ({:.1}% fewer)",
+            self.before,
+            self.before - saved,
+            saved as f64 * 100.0 / self.before.max(1) as f64
+        )?;
+        for (rule, saved) in Rule::ALL.iter().zip(self.saved) {
+            writeln!(f, "  {:<32} {:>6}", rule.to_string(), saved)?;
+        }
+        Ok(())
+    }
+}
+
+impl Assembly {
+    /// Removes instructions that do not change what the program computes, such as the
+    /// round-trips through the stack of generated code. Labels are never removed and no
+    /// rewrite looks across one, as the program may jump there from anywhere.
+    pub fn optimize(&mut self) -> Report {
+        let mut report = Report {
+            before: self.size(),
+            ..Report::default()
+        };
+        loop {
+            let mut changed = false;
+            for (i, rule) in Rule::ALL.iter().enumerate() {
+                let removed = rule.apply(&self.instructions);
+                if removed.is_empty() {
+                    continue;
+                }
+                report.saved[i] += removed.len();
+                changed = true;
+                let mut removed = removed.into_iter().peekable();
+                let code = take(&mut self.instructions)
+                    .into_iter()
+                    .zip(take(&mut self.locations));
+                (self.instructions, self.locations) = code
+                    .enumerate()
+                    .filter(|(index, _)| removed.next_if_eq(index).is_none())
+                    .map(|(_, line)| line)
+                    .unzip();
+            }
+            if !changed {
+                return report;
+            }
+        }
+    }
+}
+
+fn is_load(instruction: &Instruction, symbol: &str) -> bool {
+    matches!(instruction, Instruction::Load { data: LoadData::Label(label) } if label == symbol)
+}
+
+/// The computation and target of a C-instruction that does not jump.
+fn assignment(instruction: &Instruction) -> Option<(&Compute, Target)> {
+    match instruction {
+        Instruction::Command {
+            compute,
+            target,
+            jump: Jump::NONE,
+        } => Some((compute, *target)),
+        _ => None,
+    }
+}
+
+/// `@SP A=M M=… @SP M=M+1 @SP AM=M-1`: the pop leaves A pointing at the value just pushed
+/// and SP as it was, which is how they were before the last two loads of SP.
+fn push_pop(code: &[Instruction]) -> Vec<usize> {
+    let mut removed = Vec::new();
+    let mut i = 3;
+    while i + 4 <= code.len() {
+        let matched = is_load(&code[i - 3], "SP")
+            && matches!(assignment(&code[i - 2]), Some((Compute::M, Target::A)))
+            && matches!(assignment(&code[i - 1]), Some((_, Target::M)))
+            && is_load(&code[i], "SP")
+            && matches!(
+                assignment(&code[i + 1]),
+                Some((Compute::MplusOne, Target::M))
+            )
+            && is_load(&code[i + 2], "SP")
+            && matches!(assignment(&code[i + 3]), Some((Compute::MminOne, t)) if t == Target::A | Target::M);
+        if matched {
+            removed.extend(i..i + 4);
+            i += 4;
+        } else {
+            i += 1;
+        }
+    }
+    removed
+}
+
+/// An A-instruction loading what A already holds, or copying between D and M when both
+/// already hold the same value.
+fn redundant_load(code: &[Instruction]) -> Vec<usize> {
+    let mut removed = Vec::new();
+    let mut a: Option<&LoadData> = None;
+    for (i, instruction) in code.iter().enumerate() {
+        match instruction {
+            Instruction::Label { label: _ } => a = None,
+            Instruction::Load { data } => {
+                if a == Some(data) {
+                    removed.push(i);
+                }
+                a = Some(data);
+            }
+            Instruction::Command {
+                compute: _,
+                target,
+                jump: _,
+            } => {
+                let previous = code.get(i.wrapping_sub(1)).and_then(assignment);
+                let same = matches!(
+                    (assignment(instruction), previous),
+                    (Some((Compute::M, Target::D)), Some((Compute::D, Target::M)))
+                        | (Some((Compute::D, Target::M)), Some((Compute::M, Target::D)))
+                );
+                if same {
+                    removed.push(i);
+                }
+                if target.contains(Target::A) {
+                    a = None;
+                }
+            }
+        }
+    }
+    removed
+}
+
+/// A computation into D alone whose result is overwritten before it is read. The value may
+/// be read wherever a jump or label leads, so those end the search.
+fn dead_store(code: &[Instruction]) -> Vec<usize> {
+    let mut removed = Vec::new();
+    for (i, instruction) in code.iter().enumerate() {
+        if !matches!(assignment(instruction), Some((_, Target::D))) {
+            continue;
+        }
+        for next in &code[i + 1..] {
+            match next {
+                Instruction::Label { label: _ } => break,
+                Instruction::Load { data: _ } => continue,
+                Instruction::Command {
+                    compute,
+                    target,
+                    jump,
+                } => {
+                    if compute.reads().contains(Target::D) || !matches!(jump, Jump::NONE) {
+                        break;
+                    }
+                    if target.contains(Target::D) {
+                        removed.push(i);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    removed
+}
+
+/// `@L` and a jump without side effects, followed by `(L)`.
+fn jump_to_next(code: &[Instruction]) -> Vec<usize> {
+    let mut removed = Vec::new();
+    for (i, window) in code.windows(2).enumerate() {
+        let [
+            Instruction::Load {
+                data: LoadData::Label(destination),
+            },
+            Instruction::Command {
+                compute: _,
+                target,
+                jump,
+            },
+        ] = window
+        else {
+            continue;
+        };
+        if !target.is_empty() || matches!(jump, Jump::NONE) {
+            continue;
+        }
+        let next = code[i + 2..]
+            .iter()
+            .map_while(|instruction| match instruction {
+                Instruction::Label { label } => Some(label),
+                _ => None,
+            })
+            .any(|label| label == destination);
+        if next {
+            removed.extend([i, i + 1]);
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{
+        CodeType,
+        assembly::{Assembly, linker},
+        cpu::Cpu,
+        vm::VM,
+    };
+
+    /// The instructions left after optimizing the source.
+    fn optimize(src: &str) -> Vec<String> {
+        let mut assembly = Assembly::from_source(src, Path::new("test.asm")).unwrap();
+        assembly.optimize();
+        assembly
+            .instructions
+            .iter()
+            .map(|i| i.to_string())
+            .collect()
+    }
+
+    /// Checks that the optimizer leaves the source alone.
+    fn unchanged(src: &str) {
+        let expected: Vec<_> = src.lines().collect();
+        assert_eq!(optimize(src), expected);
+    }
+
+    #[test]
+    fn push_pop() {
+        assert_eq!(
+            optimize("@SP\nA=M\nM=D\n@SP\nM=M+1\n@SP\nAM=M-1\nD=M+1\n"),
+            ["@SP", "A=M", "M=D", "D=M+1"]
+        );
+        unchanged("@SP\nA=M\nM=D\n@SP\nM=M+1\n(L)\n@SP\nAM=M-1\nD=M+1");
+    }
+
+    #[test]
+    fn redundant_load() {
+        assert_eq!(optimize("@x\nD=M\n@x\nM=D+1\n"), ["@x", "D=M", "M=D+1"]);
+        assert_eq!(
+            optimize("@x\nM=D\nD=M\n@y\nM=D\n"),
+            ["@x", "M=D", "@y", "M=D"]
+        );
+        // A label between the loads, or A loaded through M in between
+        unchanged("@x\nD=M\n(L)\n@x\nM=D+1");
+        unchanged("@x\nA=M\n@x\nM=D");
+    }
+
+    #[test]
+    fn dead_store() {
+        assert_eq!(
+            optimize("D=1\n@x\nD=M\n@y\nM=D\n"),
+            ["@x", "D=M", "@y", "M=D"]
+        );
+        // D is read through M, on the other side of a label or where the jump leads
+        unchanged("D=1\n@x\nM=D\nD=A");
+        unchanged("D=1\n(L)\nD=0\n@y\nM=D");
+        unchanged("D=1\n@L\n0;JMP\nD=0\n@y\nM=D");
+    }
+
+    #[test]
+    fn jump_to_next() {
+        assert_eq!(optimize("@NEXT\n0;JMP\n(NEXT)\nD=0\n"), ["(NEXT)", "D=0"]);
+        assert_eq!(optimize("@B\nD;JGT\n(A)\n(B)\n"), ["(A)", "(B)"]);
+        // The jump writes M, or the label is not the next instruction
+        unchanged("@NEXT\nM=0;JMP\n(NEXT)");
+        unchanged("@NEXT\n0;JMP\nD=0\n(NEXT)");
+    }
+
+    /// Runs the translated VM program with and without optimizing it and checks that both
+    /// leave the registers, statics, stack and heap the same. R13 to R15 are scratch registers.
+    fn check(dir: &str, bootstrap: bool) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../projects")
+            .join(dir);
+        let run = |optimize: bool| {
+            let mut vm = VM::from_dir(&path).unwrap();
+            vm.set_bootstrap(bootstrap);
+            let Ok((CodeType::Assembly(mut assembly), _)) = vm.compile() else {
+                panic!("translating {} failed", dir);
+            };
+            let size = assembly.size();
+            if optimize {
+                assembly.optimize();
+                assert!(assembly.size() < size, "{} was not optimized", dir);
+            }
+            let (hex, _) = linker::link(&[assembly.object().unwrap()]).unwrap();
+            let mut cpu = Cpu::new(&hex);
+            for (register, value) in [256, 300, 400, 3000, 3010].into_iter().enumerate() {
+                cpu.ram[register] = value;
+            }
+            cpu.ram[400] = 6;
+            cpu.ram[401] = 3000;
+            cpu.run(20_000);
+            cpu
+        };
+        let (expected, actual) = (run(false), run(true));
+        assert_eq!(expected.ram[..13], actual.ram[..13], "{}", dir);
+        assert_eq!(expected.ram[16..256], actual.ram[16..256], "{}", dir);
+        assert_eq!(expected.ram[2048..], actual.ram[2048..], "{}", dir);
+        // Frames on the stack hold return addresses, which move when the code shrinks
+        let sp = expected.ram[0] as usize;
+        let stack = match bootstrap {
+            true => sp - 1..sp,
+            false => 256..sp,
+        };
+        assert_eq!(expected.ram[stack.clone()], actual.ram[stack], "{}", dir);
+    }
+
+    #[test]
+    fn same_results() {
+        for dir in [
+            "7/StackArithmetic/SimpleAdd",
+            "7/StackArithmetic/StackTest",
+            "7/MemoryAccess/BasicTest",
+            "7/MemoryAccess/PointerTest",
+            "7/MemoryAccess/StaticTest",
+            "8/ProgramFlow/BasicLoop",
+            "8/ProgramFlow/FibonacciSeries",
+        ] {
+            check(dir, false);
+        }
+        // With a bootstrap so that returns have somewhere to go
+        for dir in [
+            "8/FunctionCalls/FibonacciElement",
+            "8/FunctionCalls/NestedCall",
+            "8/FunctionCalls/StaticsTest",
+        ] {
+            check(dir, true);
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use chumsky::prelude::*;
+use text::{inline_whitespace, newline};
+
+use super::{
+    Compute, Instruction, Jump, Target,
+    expression::{BinOp, Expression},
+};
+
+/// The range of values a literal may have, negative ones are stored as two's complement.
+const LITERAL_RANGE: std::ops::RangeInclusive<i64> = i16::MIN as i64..=u16::MAX as i64;
+
+pub type Span = SimpleSpan;
+
+fn comment<'a>() -> impl Parser<'a, &'a str, (), extra::Err<Rich<'a, char, Span>>> + Clone {
+    just("//")
+        .then(any().and_is(newline().not()).repeated())
+        .ignored()
+}
+
+const SYMBOL_START: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ_.$:";
+const DIGITS: &str = "0123456789";
+
+fn symbol<'a>() -> impl Parser<'a, &'a str, String, extra::Err<Rich<'a, char, Span>>> + Clone {
+    one_of(SYMBOL_START)
+        .then(
+            one_of(SYMBOL_START)
+                .or(one_of(DIGITS))
+                .labelled("symbol character")
+                .repeated(),
+        )
+        .to_slice()
+        .map(|s: &str| s.to_string())
+        .labelled("symbol")
+}
+
+/// A 16-bit literal: decimal, negative, `0x` hexadecimal, `0b` binary or a `'c'` character.
+fn literal<'a>() -> impl Parser<'a, &'a str, i64, extra::Err<Rich<'a, char, Span>>> + Clone {
+    let digits = |digits: &'static str| one_of(digits).repeated().at_least(1).to_slice();
+    let value = choice((
+        just("0x")
+            .ignore_then(digits("0123456789abcdefABCDEF"))
+            .map(|s: &str| i64::from_str_radix(s, 16).ok()),
+        just("0b")
+            .ignore_then(digits("01"))
+            .map(|s: &str| i64::from_str_radix(s, 2).ok()),
+        just('-')
+            .ignore_then(digits(DIGITS))
+            .map(|s: &str| s.parse::<i64>().ok().map(|n| -n)),
+        digits(DIGITS).map(|s: &str| s.parse::<i64>().ok()),
+        none_of("'\r\n")
+            .labelled("character")
+            .delimited_by(just('\''), just('\''))
+            .map(|c: char| Some(c as i64)),
+    ));
+
+    value
+        .validate(|n, e, emitter| match n {
+            Some(n) if LITERAL_RANGE.contains(&n) => n,
+            _ => {
+                emitter.emit(Rich::custom(
+                    e.span(),
+                    format!(
+                        "Value {} does not fit in 16 bits, literals range from {} to {}",
+                        e.slice(),
+                        LITERAL_RANGE.start(),
+                        LITERAL_RANGE.end()
+                    ),
+                ));
+                0
+            }
+        })
+        .labelled("number")
+}
+
+/// A constant expression over literals and symbols with `+ - * / & |` and parentheses.
+pub(super) fn expression<'a>()
+-> impl Parser<'a, &'a str, Expression, extra::Err<Rich<'a, char, Span>>> + Clone {
+    recursive(|expression| {
+        let atom = choice((
+            literal().map(Expression::Literal),
+            symbol().map(Expression::Symbol),
+            expression
+                .padded_by(inline_whitespace())
+                .delimited_by(just('('), just(')')),
+        ));
+        let operator = |ops: &'static [(char, BinOp)]| {
+            choice(
+                ops.iter()
+                    .map(|&(c, op)| just(c).to(op))
+                    .collect::<Vec<_>>(),
+            )
+            .and_is(just("//").not())
+            .padded_by(inline_whitespace())
+        };
+        let binary = |operand: Boxed<'a, 'a, &'a str, Expression, _>, ops| {
+            operand
+                .clone()
+                .foldl(
+                    operator(ops).then(operand).repeated(),
+                    |left, (op, right)| Expression::Binary(Box::new(left), op, Box::new(right)),
+                )
+                .boxed()
+        };
+
+        let product = binary(atom.boxed(), &[('*', BinOp::Mul), ('/', BinOp::Div)]);
+        let sum = binary(product, &[('+', BinOp::Add), ('-', BinOp::Sub)]);
+        let and = binary(sum, &[('&', BinOp::And)]);
+        binary(and, &[('|', BinOp::Or)])
+    })
+    .labelled("expression")
+}
+
+/// Loads a literal, symbol or constant expression into A. Literals an A-instruction can not
+/// hold are loaded with the shortest sequence that produces them.
+fn load<'a>() -> impl Parser<'a, &'a str, Vec<Instruction>, extra::Err<Rich<'a, char, Span>>> + Clone
+{
+    just('@').ignore_then(expression()).map(Instruction::load)
+}
+
+/// The `A=#value` and `D=#value` pseudo-instructions, which load any 16-bit value.
+fn load_register<'a>()
+-> impl Parser<'a, &'a str, Vec<Instruction>, extra::Err<Rich<'a, char, Span>>> + Clone {
+    type Loader = fn(u16) -> Vec<Instruction>;
+    choice((
+        just('A').to(Instruction::load_a as Loader),
+        just('D').to(Instruction::load_d as Loader),
+    ))
+    .then_ignore(just("=#"))
+    .then(literal())
+    .map(|(load, value)| load(value as u16))
+}
+
+fn label<'a>() -> impl Parser<'a, &'a str, Instruction, extra::Err<Rich<'a, char, Span>>> + Clone {
+    symbol()
+        .delimited_by(just('('), just(')'))
+        .map(|label| Instruction::Label { label })
+}
+
+/// Parses `dest=comp;jump`. Each part is taken up to its delimiter and then checked, so an
+/// unknown mnemonic is reported as a whole rather than at its first unexpected character.
+fn command<'a>() -> impl Parser<'a, &'a str, Instruction, extra::Err<Rich<'a, char, Span>>> + Clone
+{
+    // Comments, labels, loads and literals end a part as well as its delimiter. Spaces may
+    // appear inside a part, as in `D = D + 1`, and are dropped when it is checked.
+    let part = |excluded: &'static str| {
+        none_of(excluded)
+            .labelled("mnemonic character")
+            .repeated()
+            .at_least(1)
+            .separated_by(inline_whitespace().at_least(1))
+            .at_least(1)
+            .to_slice()
+    };
+
+    let target = part(" \t\r\n()@#/=;")
+        .validate(|s: &str, e, emitter| {
+            Target::try_from(s).unwrap_or_else(|d| {
+                emitter.emit(Rich::custom(e.span(), d.message));
+                Target::empty()
+            })
+        })
+        .then_ignore(
+            inline_whitespace()
+                .then(just('='))
+                .then(inline_whitespace()),
+        )
+        .labelled("destination");
+    let compute = part(" \t\r\n()@#/;")
+        .validate(|s: &str, e, emitter| {
+            Compute::try_from(s).unwrap_or_else(|d| {
+                emitter.emit(Rich::custom(e.span(), d.message));
+                Compute::Zero
+            })
+        })
+        .labelled("computation");
+    let jump = inline_whitespace()
+        .then(just(';'))
+        .then(inline_whitespace())
+        .ignore_then(part(" \t\r\n()@#/").validate(|s: &str, e, emitter| {
+            Jump::try_from(s).unwrap_or_else(|d| {
+                emitter.emit(Rich::custom(e.span(), d.message));
+                Jump::NONE
+            })
+        }))
+        .labelled("jump");
+
+    target
+        .or_not()
+        .then(compute)
+        .then(jump.or_not())
+        .map(|((target, compute), jump)| Instruction::Command {
+            compute,
+            target: target.unwrap_or(Target::empty()),
+            jump: jump.unwrap_or(Jump::NONE),
+        })
+}
+
+/// A line of assembly after macro expansion.
+#[derive(Debug, Clone)]
+pub enum Item {
+    Instructions(Vec<Instruction>),
+    /// `.equ NAME value` or `.define NAME value`
+    Constant(String, Expression),
+    /// `.export NAME`, making a label visible to other objects when linking
+    Export(String),
+}
+
+fn constant<'a>() -> impl Parser<'a, &'a str, Item, extra::Err<Rich<'a, char, Span>>> + Clone {
+    let separator = inline_whitespace().at_least(1);
+    choice((just(".equ"), just(".define")))
+        .ignore_then(separator)
+        .ignore_then(symbol())
+        .then_ignore(separator)
+        .then(expression())
+        .map(|(name, value)| Item::Constant(name, value))
+}
+
+fn export<'a>() -> impl Parser<'a, &'a str, Item, extra::Err<Rich<'a, char, Span>>> + Clone {
+    just(".export")
+        .ignore_then(inline_whitespace().at_least(1))
+        .ignore_then(symbol())
+        .map(Item::Export)
+}
+
+/// Parses a whole file. A line that fails to parse is skipped after its error is recorded, so
+/// every bad line is reported in one run.
+pub fn items<'a>() -> impl Parser<'a, &'a str, Vec<(Item, Span)>, extra::Err<Rich<'a, char, Span>>>
+{
+    let item = choice((
+        constant(),
+        export(),
+        label().map(|i| Item::Instructions(vec![i])),
+        load().map(Item::Instructions),
+        load_register().map(Item::Instructions),
+        command().map(|i| Item::Instructions(vec![i])),
+    ))
+    .map_with(|i, e| (i, e.span()));
+
+    let end_of_line = inline_whitespace()
+        .ignore_then(comment().or_not())
+        .then_ignore(choice((newline(), end())).rewind());
+
+    let line = inline_whitespace()
+        .ignore_then(item.or_not())
+        .then_ignore(end_of_line)
+        .recover_with(via_parser(none_of("\r\n").repeated().to(None)));
+
+    line.separated_by(newline())
+        .collect::<Vec<_>>()
+        .map(|lines| lines.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use chumsky::Parser;
+
+    use super::{Item, items};
+
+    /// The parsed instructions and the lines with errors, counting from 1.
+    fn parse(src: &str) -> (Vec<String>, Vec<usize>) {
+        let (out, errs) = items().parse(src).into_output_errors();
+        let parsed = out
+            .unwrap_or_default()
+            .into_iter()
+            .flat_map(|(item, _)| match item {
+                Item::Instructions(instructions) => instructions,
+                Item::Constant(..) | Item::Export(_) => Vec::new(),
+            })
+            .map(|i| i.to_string())
+            .collect();
+        let lines = errs
+            .iter()
+            .map(|e| src[..e.span().start].matches('\n').count() + 1)
+            .collect();
+        (parsed, lines)
+    }
+
+    #[test]
+    fn instructions_and_comments() {
+        let (parsed, errors) =
+            parse("// Adds one\n(LOOP)\n  @i // counter\n  M=M+1\n\n  @LOOP\n  0;JMP\n");
+        assert_eq!(parsed, ["(LOOP)", "@i", "M=M+1", "@LOOP", "0;JMP"]);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn reports_every_bad_line() {
+        let src = "@\n@1\n@99999999\nD=M\nX=D\nD=D+N\n0;JMPP\n(LOOP\n@LOOP\n";
+        let (parsed, errors) = parse(src);
+        assert_eq!(errors, [1, 3, 5, 6, 7, 8]);
+        // The good lines around them are still parsed
+        assert!(parsed.contains(&"@1".to_string()));
+        assert!(parsed.contains(&"D=M".to_string()));
+        assert!(parsed.contains(&"@LOOP".to_string()));
+    }
+}
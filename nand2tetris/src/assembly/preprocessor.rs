@@ -0,0 +1,539 @@
+use std::{
+    collections::HashMap,
+    fs::read_to_string,
+    ops::Range,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::diagnostic::{Diagnostic, Location};
+
+use super::{Instruction, Target};
+
+/// How deeply macros may call each other before expansion is considered runaway recursion.
+const MAX_DEPTH: usize = 32;
+
+/// Characters that make up a symbol, and so a whole word when substituting parameters.
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$' | ':')
+}
+
+#[derive(Debug)]
+struct Macro {
+    params: Vec<String>,
+    /// Each line of the body and where it was written, builtin macros have no location
+    body: Vec<(String, Option<Location>)>,
+}
+
+impl Macro {
+    /// A macro with a fixed body generated by the compiler.
+    fn builtin(instructions: Vec<Instruction>) -> Self {
+        Macro {
+            params: Vec::new(),
+            body: instructions
+                .into_iter()
+                .map(|i| (i.to_string(), None))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Origin {
+    /// A line copied from the source, starting at this location
+    Source(Location),
+    /// A line produced by expanding the macro called at `call`
+    Expansion {
+        call: Location,
+        line: Option<Location>,
+    },
+}
+
+#[derive(Debug)]
+struct Line {
+    /// Offset of the line in the expanded text
+    start: usize,
+    origin: Origin,
+}
+
+/// Source text after macro expansion, with a map from each line back to where it came from.
+#[derive(Debug)]
+pub struct Expanded {
+    pub text: String,
+    file: Rc<str>,
+    lines: Vec<Line>,
+}
+
+impl Expanded {
+    fn line(&self, offset: usize) -> Option<&Line> {
+        let index = self.lines.partition_point(|l| l.start <= offset);
+        self.lines.get(index.checked_sub(1)?)
+    }
+
+    /// The source location of a span of the expanded text. Code from a macro expansion is
+    /// attributed to the call.
+    pub fn location(&self, span: Range<usize>) -> Location {
+        match self.line(span.start).map(|l| (l.start, &l.origin)) {
+            Some((start, Origin::Source(location))) => {
+                let offset = location.span.start;
+                Location::new(
+                    &location.file,
+                    span.start - start + offset..span.end - start + offset,
+                )
+            }
+            Some((_, Origin::Expansion { call, line: _ })) => call.clone(),
+            None => Location::new(&self.file, span),
+        }
+    }
+
+    /// Moves a diagnostic reported against the expanded text back to the source.
+    pub fn remap(&self, mut diagnostic: Diagnostic) -> Diagnostic {
+        for (location, _) in &mut diagnostic.labels {
+            if location.file == self.file {
+                *location = self.location(location.span.clone());
+            }
+        }
+        if let Some(location) = diagnostic.location.take() {
+            if let Some(Line {
+                origin:
+                    Origin::Expansion {
+                        call: _,
+                        line: Some(line),
+                    },
+                ..
+            }) = self.line(location.span.start)
+            {
+                diagnostic
+                    .labels
+                    .push((line.clone(), "in this line of the macro".to_string()));
+            }
+            diagnostic.location = Some(Box::new(self.location(location.span)));
+        }
+        diagnostic
+    }
+}
+
+struct Preprocessor {
+    /// Files currently being included, outermost first, to detect cycles
+    stack: Vec<PathBuf>,
+    macros: HashMap<String, Macro>,
+    /// Number of expansions so far, substituted for `\@` to make labels unique
+    expansions: usize,
+    out: Expanded,
+    errors: Vec<Diagnostic>,
+}
+
+/// Expands `.include "path"` directives, `.macro NAME params ... .endm` definitions and their
+/// calls. Included paths are relative to the including file. Inside a macro body, parameters
+/// are replaced as whole words and `\@` becomes a number unique to each expansion.
+/// `PUSH_D`, `POP_D` and `POP_A` are predefined with the code the VM translator uses.
+pub fn expand(src: &str, path: &Path) -> Result<Expanded, Vec<Diagnostic>> {
+    let file: Rc<str> = path.to_string_lossy().into();
+    let mut preprocessor = Preprocessor {
+        stack: path.canonicalize().into_iter().collect(),
+        macros: HashMap::from([
+            ("PUSH_D".to_string(), Macro::builtin(Instruction::push_d())),
+            (
+                "POP_D".to_string(),
+                Macro::builtin(Instruction::pop(Target::D)),
+            ),
+            (
+                "POP_A".to_string(),
+                Macro::builtin(Instruction::pop(Target::A)),
+            ),
+        ]),
+        expansions: 0,
+        out: Expanded {
+            text: String::new(),
+            file: file.clone(),
+            lines: Vec::new(),
+        },
+        errors: Vec::new(),
+    };
+    preprocessor.run(src, &file);
+    if !preprocessor.errors.is_empty() {
+        return Err(preprocessor.errors);
+    }
+    Ok(preprocessor.out)
+}
+
+/// Splits a line into its words, ignoring comments. Parameters and arguments may be
+/// separated by whitespace or commas.
+fn words(line: &str) -> Vec<&str> {
+    let code = line.split("//").next().unwrap_or_default();
+    code.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|w| !w.is_empty())
+        .collect()
+}
+
+impl Preprocessor {
+    fn run(&mut self, src: &str, file: &Rc<str>) {
+        let mut definition: Option<(String, Macro, Location)> = None;
+        let mut offset = 0;
+        for line in src.split_inclusive('\n') {
+            let content = line.trim_end_matches(['\r', '\n']);
+            let location = Location::new(file, offset..offset + content.len());
+            offset += line.len();
+            let words = words(content);
+
+            match (words.first().copied(), &mut definition) {
+                (Some(".endm"), Some(_)) => {
+                    let (name, m, _) = definition.take().unwrap();
+                    self.macros.insert(name, m);
+                }
+                (Some(".macro"), Some((_, _, start))) => self.errors.push(
+                    Diagnostic::error("nested-macro", "Macros can not be defined inside macros")
+                        .with_location(location)
+                        .with_secondary(start.clone(), "inside this macro"),
+                ),
+                (_, Some((_, m, _))) => m.body.push((content.to_string(), Some(location))),
+                (Some(".macro"), None) => match words.get(1) {
+                    Some(name) => {
+                        let m = Macro {
+                            params: words[2..].iter().map(|w| w.to_string()).collect(),
+                            body: Vec::new(),
+                        };
+                        definition = Some((name.to_string(), m, location));
+                    }
+                    None => self.errors.push(
+                        Diagnostic::error("invalid-macro", "Macro definition without a name")
+                            .with_location(location)
+                            .with_label("expected `.macro NAME params...`"),
+                    ),
+                },
+                (Some(".include"), None) => self.include(content, &location),
+                (Some(".endm"), None) => self.errors.push(
+                    Diagnostic::error("invalid-macro", "`.endm` without a macro definition")
+                        .with_location(location),
+                ),
+                (Some(name), None) if self.macros.contains_key(name) => {
+                    let args = words[1..].iter().map(|w| w.to_string()).collect();
+                    let indent = content.len() - content.trim_start().len();
+                    let call = Location::new(file, location.span.start + indent..location.span.end);
+                    self.call(name, args, &call, 0);
+                }
+                (_, None) => {
+                    self.push(line, Origin::Source(location));
+                }
+            }
+        }
+        if let Some((name, _, start)) = definition {
+            self.errors.push(
+                Diagnostic::error("invalid-macro", format!("Macro '{}' is never closed", name))
+                    .with_location(start)
+                    .with_label("expected `.endm` after this definition"),
+            );
+        }
+    }
+
+    fn include(&mut self, content: &str, location: &Location) {
+        let argument = content.split("//").next().unwrap_or_default().trim();
+        let argument = argument.trim_start_matches(".include").trim();
+        let Some(name) = argument.strip_prefix('"').and_then(|a| a.strip_suffix('"')) else {
+            self.errors.push(
+                Diagnostic::error("invalid-include", "Invalid include directive")
+                    .with_location(location.clone())
+                    .with_label("expected `.include \"path\"`"),
+            );
+            return;
+        };
+
+        let dir = Path::new(&*location.file).parent().unwrap_or(Path::new(""));
+        let path = dir.join(name);
+        let src = match path
+            .canonicalize()
+            .and_then(|c| Ok((read_to_string(&path)?, c)))
+        {
+            Ok((_, canonical)) if self.stack.contains(&canonical) => {
+                let chain: Vec<_> = self
+                    .stack
+                    .iter()
+                    .chain([&canonical])
+                    .map(|p| p.to_string_lossy())
+                    .collect();
+                self.errors.push(
+                    Diagnostic::error(
+                        "include-cycle",
+                        format!("{} includes itself", path.to_string_lossy()),
+                    )
+                    .with_location(location.clone())
+                    .with_note(format!("include chain: {}", chain.join(" -> "))),
+                );
+                return;
+            }
+            Ok((src, canonical)) => {
+                self.stack.push(canonical);
+                src
+            }
+            Err(e) => {
+                self.errors
+                    .push(Diagnostic::io(&path, e).with_location(location.clone()));
+                return;
+            }
+        };
+        self.run(&src, &path.to_string_lossy().into());
+        self.stack.pop();
+    }
+
+    fn push(&mut self, line: &str, origin: Origin) {
+        self.out.lines.push(Line {
+            start: self.out.text.len(),
+            origin,
+        });
+        self.out.text += line;
+        if !line.ends_with('\n') {
+            self.out.text.push('\n');
+        }
+    }
+
+    fn call(&mut self, name: &str, args: Vec<String>, call: &Location, depth: usize) {
+        if depth >= MAX_DEPTH {
+            self.errors.push(
+                Diagnostic::error(
+                    "macro-recursion",
+                    format!("Expanding macro '{}' does not terminate", name),
+                )
+                .with_location(call.clone())
+                .with_note(format!("macros may be nested at most {} deep", MAX_DEPTH)),
+            );
+            return;
+        }
+        let m = &self.macros[name];
+        if m.params.len() != args.len() {
+            self.errors.push(
+                Diagnostic::error(
+                    "macro-arguments",
+                    format!(
+                        "Macro '{}' takes {} arguments but {} were given",
+                        name,
+                        m.params.len(),
+                        args.len()
+                    ),
+                )
+                .with_location(call.clone()),
+            );
+            return;
+        }
+
+        self.expansions += 1;
+        let unique = self.expansions.to_string();
+        let substitutions: HashMap<&str, &str> = m
+            .params
+            .iter()
+            .map(|p| p.as_str())
+            .zip(args.iter().map(|a| a.as_str()))
+            .collect();
+        let body: Vec<_> = m
+            .body
+            .iter()
+            .map(|(line, location)| {
+                (
+                    substitute(line, &substitutions).replace("\\@", &unique),
+                    location.clone(),
+                )
+            })
+            .collect();
+
+        for (line, location) in body {
+            let words = words(&line);
+            match words.first() {
+                Some(&inner) if self.macros.contains_key(inner) => {
+                    let args = words[1..].iter().map(|w| w.to_string()).collect();
+                    self.call(inner, args, call, depth + 1);
+                }
+                _ => self.push(
+                    &line,
+                    Origin::Expansion {
+                        call: call.clone(),
+                        line: location,
+                    },
+                ),
+            }
+        }
+    }
+}
+
+/// Replaces every word of the line that is a parameter with its argument.
+fn substitute(line: &str, substitutions: &HashMap<&str, &str>) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        let len = match is_symbol_char(c) {
+            true => rest.find(|c| !is_symbol_char(c)).unwrap_or(rest.len()),
+            false => c.len_utf8(),
+        };
+        let (word, tail) = rest.split_at(len);
+        out += substitutions.get(word).copied().unwrap_or(word);
+        rest = tail;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use crate::assembly::Assembly;
+
+    use super::{MAX_DEPTH, expand};
+
+    fn text(src: &str) -> String {
+        expand(src, Path::new("test.asm")).unwrap().text
+    }
+
+    fn errors(src: &str) -> Vec<&'static str> {
+        expand(src, Path::new("test.asm"))
+            .unwrap_err()
+            .iter()
+            .map(|e| e.code)
+            .collect()
+    }
+
+    #[test]
+    fn parameters() {
+        assert_eq!(
+            text(".macro INC x\n@x // x += 1\nM=M+1\n@xs\n.endm\nINC i\nINC j\n"),
+            "@i // i += 1\nM=M+1\n@xs\n@j // j += 1\nM=M+1\n@xs\n"
+        );
+        assert_eq!(
+            text(".macro COPY from, to\n@from\nD=M\n@to\nM=D\n.endm\nCOPY a,b\n"),
+            "@a\nD=M\n@b\nM=D\n"
+        );
+    }
+
+    #[test]
+    fn unique_labels() {
+        assert_eq!(
+            text(".macro SKIP\n@end\\@\n0;JMP\n(end\\@)\n.endm\nSKIP\nSKIP\n"),
+            "@end1\n0;JMP\n(end1)\n@end2\n0;JMP\n(end2)\n"
+        );
+    }
+
+    #[test]
+    fn nested_calls() {
+        assert_eq!(
+            text(".macro PUSH_ONE\nD=1\nPUSH_D\n.endm\nPUSH_ONE\n"),
+            "D=1\n@SP\nA=M\nM=D\n@SP\nM=M+1\n"
+        );
+        assert_eq!(text("POP_A\n"), "@SP\nAM=M-1\nA=M\n");
+    }
+
+    #[test]
+    fn invalid_macros() {
+        assert_eq!(errors(".macro A x\n.endm\nA\n"), ["macro-arguments"]);
+        assert_eq!(
+            errors(".macro LOOP\nLOOP\n.endm\nLOOP\n"),
+            ["macro-recursion"]
+        );
+        assert_eq!(errors(".macro A\n@1\n"), ["invalid-macro"]);
+        assert_eq!(errors(".endm\n"), ["invalid-macro"]);
+        assert_eq!(errors(".macro A\n.macro B\n.endm\n"), ["nested-macro"]);
+    }
+
+    /// Writes the files into a fresh directory, returning the directory.
+    fn files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        for (path, src) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, src).unwrap();
+        }
+        dir
+    }
+
+    fn expand_file(path: &Path) -> Result<String, Vec<&'static str>> {
+        let src = fs::read_to_string(path).unwrap();
+        expand(&src, path)
+            .map(|e| e.text)
+            .map_err(|errors| errors.iter().map(|e| e.code).collect())
+    }
+
+    #[test]
+    fn relative_includes() {
+        let dir = files(
+            "include-relative",
+            &[
+                ("Main.asm", ".include \"lib/Util.asm\"\n@main\n"),
+                ("lib/Util.asm", ".include \"Macros.asm\"\nINC util\n"),
+                ("lib/Macros.asm", ".macro INC x\n@x\nM=M+1\n.endm\n"),
+            ],
+        );
+        let text = expand_file(&dir.join("Main.asm"));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(text.unwrap(), "@util\nM=M+1\n@main\n");
+    }
+
+    #[test]
+    fn include_errors() {
+        let dir = files(
+            "include-errors",
+            &[
+                ("A.asm", ".include \"B.asm\"\n"),
+                ("B.asm", ".include \"A.asm\"\n"),
+                ("Self.asm", ".include \"Self.asm\"\n"),
+                ("Missing.asm", ".include \"Nowhere.asm\"\n"),
+                ("Unquoted.asm", ".include B.asm\n"),
+            ],
+        );
+        let results: Vec<_> = ["A", "Self", "Missing", "Unquoted"]
+            .iter()
+            .map(|name| expand_file(&dir.join(name).with_extension("asm")))
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            results,
+            [
+                Err(vec!["include-cycle"]),
+                Err(vec!["include-cycle"]),
+                Err(vec!["io"]),
+                Err(vec!["invalid-include"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn deep_includes() {
+        // Only macro calls are limited to MAX_DEPTH, a long chain of distinct files is fine
+        let chain: Vec<_> = (0..=MAX_DEPTH + 1)
+            .map(|i| {
+                (
+                    format!("F{}.asm", i),
+                    format!(".include \"F{}.asm\"\n@{}\n", i + 1, i),
+                )
+            })
+            .chain([(format!("F{}.asm", MAX_DEPTH + 2), "@end\n".to_string())])
+            .collect();
+        let chain: Vec<_> = chain
+            .iter()
+            .map(|(path, src)| (path.as_str(), src.as_str()))
+            .collect();
+        let dir = files("include-deep", &chain);
+        let text = expand_file(&dir.join("F0.asm"));
+        fs::remove_dir_all(&dir).unwrap();
+        let text = text.unwrap();
+        assert!(text.starts_with("@end\n@33\n"));
+        assert!(text.ends_with("@1\n@0\n"));
+    }
+
+    #[test]
+    fn errors_name_included_file() {
+        let dir = files(
+            "include-location",
+            &[
+                ("Main.asm", "@1\n.include \"Lib.asm\"\n"),
+                ("Lib.asm", "D=M\nD=D+N\n"),
+            ],
+        );
+        let errors = Assembly::from_file(&dir.join("Main.asm")).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(errors.len(), 1);
+        let location = errors[0].location.as_ref().unwrap();
+        assert!(location.file.ends_with("Lib.asm"), "{}", location.file);
+        let line = "D=M\nD=D+N\n"[..location.span.start].matches('\n').count() + 1;
+        assert_eq!(line, 2);
+    }
+}
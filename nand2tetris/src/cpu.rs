@@ -0,0 +1,86 @@
+use crate::hex::Hex;
+
+pub const MEMORY_SIZE: usize = 0x8000;
+
+/// Emulates the Hack CPU together with its instruction and data memory.
+pub struct Cpu {
+    rom: Vec<u16>,
+    pub ram: Vec<u16>,
+    pub a: u16,
+    pub d: u16,
+    pub pc: u16,
+    pub time: u64,
+}
+
+impl Cpu {
+    pub fn new(hex: &Hex) -> Self {
+        let mut rom = hex.instructions.clone();
+        rom.resize(MEMORY_SIZE, 0);
+        Cpu {
+            rom,
+            ram: vec![0; MEMORY_SIZE],
+            a: 0,
+            d: 0,
+            pc: 0,
+            time: 0,
+        }
+    }
+
+    fn alu(x: u16, y: u16, control: u16) -> u16 {
+        let x = if control & 0b100000 != 0 { 0 } else { x };
+        let x = if control & 0b010000 != 0 { !x } else { x };
+        let y = if control & 0b001000 != 0 { 0 } else { y };
+        let y = if control & 0b000100 != 0 { !y } else { y };
+        let out = if control & 0b000010 != 0 {
+            x.wrapping_add(y)
+        } else {
+            x & y
+        };
+        if control & 0b000001 != 0 { !out } else { out }
+    }
+
+    /// Executes the instruction at PC.
+    pub fn step(&mut self) {
+        let instr = self.rom[(self.pc as usize) % MEMORY_SIZE];
+        self.time += 1;
+        if instr & 0x8000 == 0 {
+            self.a = instr;
+            self.pc = self.pc.wrapping_add(1);
+            return;
+        }
+
+        let address = (self.a as usize) % MEMORY_SIZE;
+        let y = if instr & 0x1000 != 0 {
+            self.ram[address]
+        } else {
+            self.a
+        };
+        let out = Self::alu(self.d, y, (instr >> 6) & 0b111111);
+
+        if instr & 0b001000 != 0 {
+            self.ram[address] = out;
+        }
+        if instr & 0b100000 != 0 {
+            self.a = out;
+        }
+        if instr & 0b010000 != 0 {
+            self.d = out;
+        }
+
+        let value = out as i16;
+        let jump = (instr & 0b100 != 0 && value < 0)
+            || (instr & 0b010 != 0 && value == 0)
+            || (instr & 0b001 != 0 && value > 0);
+        self.pc = if jump {
+            address as u16
+        } else {
+            self.pc.wrapping_add(1)
+        };
+    }
+
+    pub fn run(&mut self, cycles: u64) {
+        for _ in 0..cycles {
+            self.step();
+        }
+    }
+}
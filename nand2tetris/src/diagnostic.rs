@@ -0,0 +1,276 @@
+use std::{collections::HashMap, fmt::Display, fs::read_to_string, ops::Range, rc::Rc};
+
+use ariadne::{Color, Config, IndexType, Label, Report, ReportKind, Source};
+use chumsky::{error::Rich, span::SimpleSpan};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Note,
+    Warning,
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Note => f.write_str("Note"),
+            Severity::Warning => f.write_str("Warning"),
+            Severity::Error => f.write_str("Error"),
+        }
+    }
+}
+
+/// A byte range in a source file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: Rc<str>,
+    pub span: Range<usize>,
+}
+
+impl Location {
+    pub fn new(file: &Rc<str>, span: Range<usize>) -> Self {
+        Location {
+            file: file.clone(),
+            span,
+        }
+    }
+}
+
+/// The location and label are boxed to keep `Result<_, Diagnostic>` small, as most results
+/// are not errors.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    pub location: Option<Box<Location>>,
+    /// Message shown at the primary location
+    pub label: Option<Box<str>>,
+    /// Additional locations related to the diagnostic
+    pub labels: Vec<(Location, String)>,
+    pub notes: Vec<String>,
+}
+
+/// Lets `?` turn a single diagnostic into the list most APIs return.
+impl From<Diagnostic> for Vec<Diagnostic> {
+    fn from(value: Diagnostic) -> Self {
+        vec![value]
+    }
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, code: &'static str, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity,
+            code,
+            message: message.into(),
+            location: None,
+            label: None,
+            labels: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn error(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(Severity::Error, code, message)
+    }
+
+    pub fn warning(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(Severity::Warning, code, message)
+    }
+
+    /// An error for a failed file operation.
+    pub fn io(path: &std::path::Path, err: std::io::Error) -> Self {
+        Self::error("io", format!("{}: {}", path.to_string_lossy(), err))
+    }
+
+    /// Converts a parser error, turning its contexts into secondary labels.
+    pub fn parse(e: Rich<'_, char, SimpleSpan>, file: &Rc<str>) -> Self {
+        let e = e.map_token(|c| c.escape_debug().to_string());
+        let mut diagnostic = Self::error("parse", e.to_string())
+            .with_location(Location::new(file, e.span().into_range()))
+            .with_label(e.reason().to_string());
+        for (label, span) in e.contexts() {
+            diagnostic = diagnostic.with_secondary(
+                Location::new(file, span.into_range()),
+                format!("while parsing this {}", label),
+            );
+        }
+        diagnostic
+    }
+
+    pub fn with_location(mut self, location: Location) -> Self {
+        self.location = Some(Box::new(location));
+        self
+    }
+
+    /// Sets the location unless one is already known.
+    pub fn or_location(mut self, location: Option<&Location>) -> Self {
+        if self.location.is_none() {
+            self.location = location.cloned().map(Box::new);
+        }
+        self
+    }
+
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into().into_boxed_str());
+        self
+    }
+
+    pub fn with_secondary(mut self, location: Location, label: impl Into<String>) -> Self {
+        self.labels.push((location, label.into()));
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    fn print_plain(&self) {
+        eprintln!("[{}] {}: {}", self.code, self.severity, self.message);
+        if let Some(location) = &self.location {
+            eprintln!("  --> {}:{:?}", location.file, location.span);
+        }
+        for note in &self.notes {
+            eprintln!("  Note: {}", note);
+        }
+    }
+
+    fn print_with(&self, cache: &mut SourceCache) {
+        let Some(location) = &self.location else {
+            return self.print_plain();
+        };
+        if !self
+            .labels
+            .iter()
+            .map(|(l, _)| &l.file)
+            .chain([&location.file])
+            .all(|f| cache.load(f))
+        {
+            return self.print_plain();
+        }
+
+        let kind = match self.severity {
+            Severity::Error => ReportKind::Error,
+            Severity::Warning => ReportKind::Warning,
+            Severity::Note => ReportKind::Advice,
+        };
+        let color = match self.severity {
+            Severity::Error => Color::Red,
+            Severity::Warning => Color::Yellow,
+            Severity::Note => Color::Blue,
+        };
+        let mut primary =
+            Label::new((location.file.clone(), location.span.clone())).with_color(color);
+        if let Some(label) = &self.label {
+            primary = primary.with_message(&**label);
+        }
+        let mut report = Report::build(kind, (location.file.clone(), location.span.clone()))
+            .with_config(Config::new().with_index_type(IndexType::Byte))
+            .with_code(self.code)
+            .with_message(&self.message)
+            .with_label(primary)
+            .with_labels(self.labels.iter().map(|(l, message)| {
+                Label::new((l.file.clone(), l.span.clone()))
+                    .with_message(message)
+                    .with_color(Color::Yellow)
+            }));
+        for note in &self.notes {
+            report = report.with_note(note);
+        }
+        // The sources were loaded above, so this only fails if stderr is unwritable
+        if report.finish().eprint(cache).is_err() {
+            self.print_plain();
+        }
+    }
+}
+
+/// Number of single character insertions, deletions, substitutions and swaps of neighbours
+/// turning `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    // Distances between prefixes of `a` and `b`, one row per prefix of `a`
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+/// A "did you mean" hint naming the candidates closest to a misspelled value, if any are
+/// close enough to be a likely typo.
+pub fn suggest<'a>(value: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<String> {
+    let scored: Vec<_> = candidates
+        .into_iter()
+        .map(|c| (edit_distance(value, c), c))
+        .filter(|(distance, _)| *distance <= (value.chars().count() / 3).max(1))
+        .collect();
+    let best = scored.iter().map(|(distance, _)| *distance).min()?;
+    let closest: Vec<_> = scored
+        .into_iter()
+        .filter(|(distance, _)| *distance == best)
+        .map(|(_, c)| c)
+        .collect();
+    Some(match closest.split_last() {
+        Some((last, [])) => format!("did you mean {}?", last),
+        Some((last, rest)) => format!("did you mean {} or {}?", rest.join(", "), last),
+        None => unreachable!("at least one candidate has the best distance"),
+    })
+}
+
+/// Source files loaded on demand to render diagnostics.
+#[derive(Default)]
+struct SourceCache {
+    files: HashMap<Rc<str>, Option<Source<String>>>,
+}
+
+impl SourceCache {
+    fn load(&mut self, file: &Rc<str>) -> bool {
+        self.files
+            .entry(file.clone())
+            .or_insert_with(|| read_to_string(&**file).ok().map(Source::from))
+            .is_some()
+    }
+}
+
+impl ariadne::Cache<Rc<str>> for SourceCache {
+    type Storage = String;
+
+    fn fetch(&mut self, id: &Rc<str>) -> Result<&Source<String>, impl std::fmt::Debug> {
+        self.files
+            .get(id)
+            .and_then(|s| s.as_ref())
+            .ok_or(format!("File {} is not loaded", id))
+    }
+
+    fn display<'a>(&self, id: &'a Rc<str>) -> Option<impl Display + 'a> {
+        Some(id.clone())
+    }
+}
+
+/// Prints all diagnostics to stderr.
+pub fn print(diagnostics: &[Diagnostic]) {
+    let mut cache = SourceCache::default();
+    for diagnostic in diagnostics {
+        diagnostic.print_with(&mut cache);
+    }
+}
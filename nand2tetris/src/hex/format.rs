@@ -0,0 +1,692 @@
+use std::{collections::BTreeMap, io::Write, ops::Range, rc::Rc};
+
+use clap::ValueEnum;
+
+use crate::{
+    assembly::ROM_SIZE,
+    diagnostic::{Diagnostic, Location},
+};
+
+/// The ways a ROM image can be stored. Words are always 16 bits wide.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// One line of 16 binary digits per word, as used by the nand2tetris tools
+    #[default]
+    #[value(name = "hack")]
+    Hack,
+    /// Two bytes per word, most significant byte first
+    #[value(name = "raw-be")]
+    RawBigEndian,
+    /// Two bytes per word, least significant byte first
+    #[value(name = "raw-le")]
+    RawLittleEndian,
+    /// Intel HEX records with byte addresses, two bytes per word, most significant first
+    #[value(name = "intel-hex")]
+    IntelHex,
+    /// Binary digits for Verilog `$readmemb`
+    #[value(name = "readmemb")]
+    Readmemb,
+    /// Hexadecimal digits for Verilog `$readmemh`
+    #[value(name = "readmemh")]
+    Readmemh,
+    /// Altera/Intel Memory Initialization File
+    #[value(name = "mif")]
+    Mif,
+    /// Xilinx coefficient file
+    #[value(name = "coe")]
+    Coe,
+    /// Logisim ROM image (`v2.0 raw`)
+    #[value(name = "logisim")]
+    Logisim,
+}
+
+/// Words per line or record in the formats that group them.
+const WORDS_PER_LINE: usize = 8;
+
+impl Format {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Hack => "hack",
+            Format::RawBigEndian | Format::RawLittleEndian => "bin",
+            Format::IntelHex => "hex",
+            Format::Readmemb | Format::Readmemh => "mem",
+            Format::Mif => "mif",
+            Format::Coe => "coe",
+            Format::Logisim => "rom",
+        }
+    }
+
+    /// Guesses the format of a file from its extension and, where the extension is shared
+    /// by several formats, its contents. Raw binary files are taken to be big-endian.
+    pub fn detect(extension: &str, src: &[u8]) -> Option<Self> {
+        match extension {
+            "hack" => Some(Format::Hack),
+            "bin" => Some(Format::RawBigEndian),
+            "hex" => Some(Format::IntelHex),
+            "mem" => {
+                let src = strip_comments(&String::from_utf8_lossy(src), "//", None);
+                let first = words(&src).next().map(|(word, _)| word);
+                match first.is_some_and(|w| w.len() == 16) {
+                    true => Some(Format::Readmemb),
+                    false => Some(Format::Readmemh),
+                }
+            }
+            "mif" => Some(Format::Mif),
+            "coe" => Some(Format::Coe),
+            "rom" => Some(Format::Logisim),
+            _ => None,
+        }
+    }
+
+    pub fn write(&self, words: &[u16], out: &mut impl Write) -> std::io::Result<()> {
+        match self {
+            Format::Hack | Format::Readmemb => {
+                for word in words {
+                    writeln!(out, "{:016b}", word)?;
+                }
+            }
+            Format::Readmemh => {
+                for word in words {
+                    writeln!(out, "{:04x}", word)?;
+                }
+            }
+            Format::RawBigEndian => {
+                let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_be_bytes()).collect();
+                out.write_all(&bytes)?;
+            }
+            Format::RawLittleEndian => {
+                let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+                out.write_all(&bytes)?;
+            }
+            Format::IntelHex => {
+                for (i, chunk) in words.chunks(WORDS_PER_LINE).enumerate() {
+                    let data: Vec<u8> = chunk.iter().flat_map(|w| w.to_be_bytes()).collect();
+                    let address = (i * WORDS_PER_LINE * 2) as u16;
+                    write_record(out, address, 0x00, &data)?;
+                }
+                write_record(out, 0, 0x01, &[])?;
+            }
+            Format::Mif => {
+                writeln!(out, "WIDTH=16;")?;
+                writeln!(out, "DEPTH={};", words.len().max(1))?;
+                writeln!(out, "ADDRESS_RADIX=UNS;")?;
+                writeln!(out, "DATA_RADIX=BIN;")?;
+                writeln!(out, "CONTENT BEGIN")?;
+                for (address, word) in words.iter().enumerate() {
+                    writeln!(out, "    {} : {:016b};", address, word)?;
+                }
+                writeln!(out, "END;")?;
+            }
+            Format::Coe => {
+                writeln!(out, "memory_initialization_radix=2;")?;
+                writeln!(out, "memory_initialization_vector=")?;
+                for (i, word) in words.iter().enumerate() {
+                    let end = if i + 1 == words.len() { ';' } else { ',' };
+                    writeln!(out, "{:016b}{}", word, end)?;
+                }
+                if words.is_empty() {
+                    writeln!(out, "0;")?;
+                }
+            }
+            Format::Logisim => {
+                writeln!(out, "v2.0 raw")?;
+                // Runs of the same word are written as `count*word`, as Logisim does
+                let mut runs: Vec<(usize, u16)> = Vec::new();
+                for &word in words {
+                    match runs.last_mut() {
+                        Some((count, last)) if *last == word => *count += 1,
+                        _ => runs.push((1, word)),
+                    }
+                }
+                for line in runs.chunks(WORDS_PER_LINE) {
+                    let line: Vec<_> = line
+                        .iter()
+                        .map(|&(count, word)| match count {
+                            1 => format!("{:x}", word),
+                            _ => format!("{}*{:x}", count, word),
+                        })
+                        .collect();
+                    writeln!(out, "{}", line.join(" "))?;
+                }
+            }
+        }
+        out.flush()
+    }
+
+    pub fn read(&self, src: &[u8], file: &Rc<str>) -> Result<Vec<u16>, Vec<Diagnostic>> {
+        match self {
+            Format::RawBigEndian | Format::RawLittleEndian => {
+                if !src.len().is_multiple_of(2) {
+                    return Err(Diagnostic::error(
+                        "invalid-binary",
+                        format!(
+                            "{} has {} bytes, which is not a whole number of 16-bit words",
+                            file,
+                            src.len()
+                        ),
+                    )
+                    .into());
+                }
+                if src.len() / 2 > ROM_SIZE {
+                    return Err(Diagnostic::error(
+                        "invalid-binary",
+                        format!(
+                            "Image extends to word {}, beyond the end of ROM",
+                            src.len() / 2 - 1
+                        ),
+                    )
+                    .with_note(format!("ROM holds {} words", ROM_SIZE))
+                    .into());
+                }
+                Ok(src
+                    .chunks(2)
+                    .map(|b| match self {
+                        Format::RawBigEndian => u16::from_be_bytes([b[0], b[1]]),
+                        _ => u16::from_le_bytes([b[0], b[1]]),
+                    })
+                    .collect())
+            }
+            _ => {
+                let src = std::str::from_utf8(src).map_err(|e| {
+                    Diagnostic::error("invalid-binary", format!("{} is not text: {}", file, e))
+                })?;
+                let mut reader = Reader {
+                    file: file.clone(),
+                    errors: Vec::new(),
+                };
+                let words = match self {
+                    Format::Hack => reader.hack(src),
+                    Format::IntelHex => reader.intel_hex(src),
+                    Format::Readmemb => reader.readmem(src, 2),
+                    Format::Readmemh => reader.readmem(src, 16),
+                    Format::Mif => reader.mif(src),
+                    Format::Coe => reader.coe(src),
+                    Format::Logisim => reader.logisim(src),
+                    Format::RawBigEndian | Format::RawLittleEndian => unreachable!(),
+                };
+                match reader.errors.is_empty() {
+                    true => Ok(words),
+                    false => Err(reader.errors),
+                }
+            }
+        }
+    }
+}
+
+fn write_record(out: &mut impl Write, address: u16, kind: u8, data: &[u8]) -> std::io::Result<()> {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend(address.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend(data);
+    let checksum = bytes
+        .iter()
+        .fold(0u8, |sum, b| sum.wrapping_add(*b))
+        .wrapping_neg();
+    bytes.push(checksum);
+    let digits: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    writeln!(out, ":{}", digits)
+}
+
+/// Replaces comments by spaces, so offsets into the result are offsets into the source.
+/// Line comments start with `line`, block comments are enclosed in `block`.
+fn strip_comments(src: &str, line: &str, block: Option<char>) -> String {
+    let mut out = String::with_capacity(src.len());
+    let mut rest = src;
+    let blank = |text: &str| -> String {
+        text.chars()
+            .map(|c| if c == '\n' { '\n' } else { ' ' })
+            .collect()
+    };
+    while !rest.is_empty() {
+        let line_start = rest.find(line);
+        let block_start = block.and_then(|b| rest.find(b));
+        match (line_start, block_start) {
+            (Some(l), b) if b.is_none_or(|b| l < b) => {
+                let end = rest[l..].find('\n').map_or(rest.len(), |e| l + e);
+                out += &rest[..l];
+                out += &blank(&rest[l..end]);
+                rest = &rest[end..];
+            }
+            (_, Some(b)) => {
+                let delimiter = block.unwrap_or_default();
+                let end = rest[b + 1..]
+                    .find(delimiter)
+                    .map_or(rest.len(), |e| b + e + 2);
+                out += &rest[..b];
+                out += &blank(&rest[b..end]);
+                rest = &rest[end..];
+            }
+            _ => {
+                out += rest;
+                rest = "";
+            }
+        }
+    }
+    out
+}
+
+/// Offset of a slice of `src` from its start.
+fn offset(src: &str, part: &str) -> usize {
+    part.as_ptr() as usize - src.as_ptr() as usize
+}
+
+/// The whitespace separated words of a text, with their offsets.
+fn words(src: &str) -> impl Iterator<Item = (&str, usize)> {
+    src.split_whitespace().map(move |w| (w, offset(src, w)))
+}
+
+/// Collects the errors of reading a text format.
+struct Reader {
+    file: Rc<str>,
+    errors: Vec<Diagnostic>,
+}
+
+impl Reader {
+    fn error(&mut self, span: Range<usize>, message: impl Into<String>, label: &str) {
+        self.errors.push(
+            Diagnostic::error("invalid-binary", message)
+                .with_location(Location::new(&self.file, span))
+                .with_label(label),
+        );
+    }
+
+    /// Reports words that would end up beyond ROM, before a corrupt file makes us allocate
+    /// far more memory than ROM has. `end` is one past the last address written.
+    fn fits(&mut self, span: Range<usize>, end: usize) -> bool {
+        if end <= ROM_SIZE {
+            return true;
+        }
+        self.error(
+            span,
+            format!("Image extends to word {}, beyond the end of ROM", end - 1),
+            &format!("ROM holds {} words", ROM_SIZE),
+        );
+        false
+    }
+
+    /// Parses a word in the given radix, allowing `_` as a separator as Verilog does.
+    fn value(&mut self, word: &str, offset: usize, radix: u32) -> u16 {
+        let digits: String = word.chars().filter(|c| *c != '_').collect();
+        match u16::from_str_radix(&digits, radix) {
+            Ok(value) => value,
+            Err(_) => {
+                self.error(
+                    offset..offset + word.len(),
+                    format!("Invalid value '{}'", word),
+                    &format!("expected a 16-bit number in base {}", radix),
+                );
+                0
+            }
+        }
+    }
+
+    fn hack(&mut self, src: &str) -> Vec<u16> {
+        let mut words = Vec::new();
+        for line in src.lines() {
+            let content = line.trim();
+            if content.is_empty() {
+                continue;
+            }
+            let start = offset(src, content);
+            if !self.fits(start..start + content.len(), words.len() + 1) {
+                break;
+            }
+            match u16::from_str_radix(content, 2) {
+                Ok(value) if content.len() == 16 => words.push(value),
+                _ => self.error(
+                    start..start + content.len(),
+                    "Invalid instruction",
+                    "expected exactly 16 binary digits",
+                ),
+            }
+        }
+        words
+    }
+
+    fn intel_hex(&mut self, src: &str) -> Vec<u16> {
+        let mut bytes: BTreeMap<u32, u8> = BTreeMap::new();
+        let mut base: u32 = 0;
+        for (record, start) in words(src) {
+            let span = start..start + record.len();
+            let data = record
+                .strip_prefix(':')
+                .filter(|r| r.len().is_multiple_of(2))
+                .and_then(|r| {
+                    (0..r.len())
+                        .step_by(2)
+                        .map(|i| u8::from_str_radix(&r[i..i + 2], 16).ok())
+                        .collect::<Option<Vec<u8>>>()
+                });
+            let Some(data) = data.filter(|d| d.len() >= 5 && d.len() == d[0] as usize + 5) else {
+                self.error(
+                    span,
+                    "Invalid Intel HEX record",
+                    "expected `:` followed by length, address, type, data and checksum",
+                );
+                continue;
+            };
+            if data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+                self.error(span, "Wrong checksum", "this record is corrupted");
+                continue;
+            }
+            let address = u16::from_be_bytes([data[1], data[2]]) as u32;
+            let payload = &data[4..data.len() - 1];
+            match (data[3], payload) {
+                (0x00, _) => {
+                    let end = (base + address) as usize + payload.len();
+                    if !self.fits(span, end.div_ceil(2)) {
+                        continue;
+                    }
+                    for (i, byte) in payload.iter().enumerate() {
+                        bytes.insert(base + address + i as u32, *byte);
+                    }
+                }
+                (0x01, _) => break,
+                (0x02, &[high, low]) => base = (u16::from_be_bytes([high, low]) as u32) << 4,
+                (0x04, &[high, low]) => base = (u16::from_be_bytes([high, low]) as u32) << 16,
+                // Start addresses mean nothing to the Hack computer
+                (0x03 | 0x05, _) => (),
+                (kind, _) => self.error(
+                    span,
+                    format!("Unsupported record type {:02X}", kind),
+                    "expected a data, end of file or address record",
+                ),
+            }
+        }
+        let end = bytes.last_key_value().map_or(0, |(a, _)| a / 2 + 1);
+        let byte = |a| bytes.get(&a).copied().unwrap_or_default();
+        (0..end)
+            .map(|i| u16::from_be_bytes([byte(2 * i), byte(2 * i + 1)]))
+            .collect()
+    }
+
+    /// Verilog memory files, where `@address` moves to a hexadecimal word address.
+    fn readmem(&mut self, src: &str, radix: u32) -> Vec<u16> {
+        let src = strip_comments(src, "//", None);
+        let mut words_read = Vec::new();
+        let mut address = 0;
+        for (word, start) in words(&src) {
+            match word.strip_prefix('@') {
+                Some(target) => match usize::from_str_radix(target, 16) {
+                    Ok(target) => address = target,
+                    Err(_) => self.error(
+                        start..start + word.len(),
+                        format!("Invalid address '{}'", word),
+                        "expected `@` followed by a hexadecimal address",
+                    ),
+                },
+                None => {
+                    if !self.fits(start..start + word.len(), address + 1) {
+                        break;
+                    }
+                    let value = self.value(word, start, radix);
+                    store(&mut words_read, address, value);
+                    address += 1;
+                }
+            }
+        }
+        words_read
+    }
+
+    fn mif(&mut self, src: &str) -> Vec<u16> {
+        let src = strip_comments(src, "--", Some('%'));
+        let upper = src.to_ascii_uppercase();
+        let Some(begin) = upper
+            .find("CONTENT")
+            .and_then(|c| upper[c..].find("BEGIN").map(|b| c + b + "BEGIN".len()))
+        else {
+            self.error(
+                0..0,
+                "Memory initialization file has no content",
+                "expected `CONTENT BEGIN`",
+            );
+            return Vec::new();
+        };
+
+        let mut address_radix = 16;
+        let mut data_radix = 16;
+        for setting in src[..begin].split(';') {
+            let Some((key, value)) = setting.split_once('=') else {
+                continue;
+            };
+            let radix = match value.trim().to_ascii_uppercase().as_str() {
+                "BIN" => 2,
+                "OCT" => 8,
+                "DEC" | "UNS" => 10,
+                _ => 16,
+            };
+            match key.trim().to_ascii_uppercase().as_str() {
+                "ADDRESS_RADIX" => address_radix = radix,
+                "DATA_RADIX" => data_radix = radix,
+                _ => (),
+            }
+        }
+
+        let mut words_read = Vec::new();
+        for entry in src[begin..].split(';').map(str::trim) {
+            let start = offset(&src, entry);
+            let span = start..start + entry.len();
+            if entry.is_empty() || entry.eq_ignore_ascii_case("END") {
+                continue;
+            }
+            let Some((addresses, values)) = entry.split_once(':') else {
+                self.error(
+                    span,
+                    "Invalid memory initialization entry",
+                    "expected `address : value`",
+                );
+                continue;
+            };
+            let addresses = addresses.trim();
+            let (first, last) = match addresses
+                .strip_prefix('[')
+                .and_then(|a| a.strip_suffix(']'))
+                .and_then(|a| a.split_once(".."))
+            {
+                Some((first, last)) => (first.trim(), last.trim()),
+                None => (addresses, addresses),
+            };
+            let (Ok(first), Ok(last)) = (
+                usize::from_str_radix(first, address_radix),
+                usize::from_str_radix(last, address_radix),
+            ) else {
+                self.error(
+                    span,
+                    format!("Invalid address '{}'", addresses),
+                    "expected an address or a range `[first..last]`",
+                );
+                continue;
+            };
+            let values: Vec<u16> = words(values)
+                .map(|(v, _)| self.value(v, offset(&src, v), data_radix))
+                .collect();
+            // A range repeats its values, a single address is followed by consecutive ones
+            let count = last
+                .saturating_add(1)
+                .saturating_sub(first)
+                .max(values.len());
+            if !self.fits(span, first.saturating_add(count)) {
+                continue;
+            }
+            for (i, value) in values.iter().cycle().take(count).enumerate() {
+                store(&mut words_read, first + i, *value);
+            }
+        }
+        words_read
+    }
+
+    fn coe(&mut self, src: &str) -> Vec<u16> {
+        // Comments are lines starting with `;`, other semicolons end a statement
+        let cleaned: String = src
+            .split_inclusive('\n')
+            .map(|line| match line.trim_start().starts_with(';') {
+                true => strip_comments(line, ";", None),
+                false => line.to_string(),
+            })
+            .collect();
+        let mut radix = 10;
+        let mut words_read = Vec::new();
+        for statement in cleaned.split(';') {
+            let Some((key, value)) = statement.split_once('=') else {
+                continue;
+            };
+            match key.trim().to_ascii_lowercase().as_str() {
+                "memory_initialization_radix" => {
+                    let value = value.trim();
+                    radix = match value.parse() {
+                        Ok(r @ (2 | 8 | 10 | 16)) => r,
+                        _ => {
+                            let start = offset(&cleaned, value);
+                            self.error(
+                                start..start + value.len(),
+                                format!("Unsupported radix '{}'", value),
+                                "expected 2, 8, 10 or 16",
+                            );
+                            10
+                        }
+                    }
+                }
+                "memory_initialization_vector" => {
+                    for part in value.split([',', ' ', '\t', '\r', '\n']) {
+                        if !part.is_empty() {
+                            let value = self.value(part, offset(&cleaned, part), radix);
+                            words_read.push(value);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        words_read
+    }
+
+    fn logisim(&mut self, src: &str) -> Vec<u16> {
+        let src = strip_comments(src, "#", None);
+        let mut tokens = words(&src);
+        let mut words_read = Vec::new();
+        if !matches!(
+            (tokens.next(), tokens.next()),
+            (Some(("v2.0", _)), Some(("raw", _)))
+        ) {
+            self.error(
+                0..0,
+                "Not a Logisim image",
+                "expected the header `v2.0 raw`",
+            );
+            return words_read;
+        }
+        for (token, start) in tokens {
+            let (count, value) = match token.split_once('*') {
+                Some((count, value)) => match count.parse::<usize>() {
+                    Ok(count) => (count, value),
+                    Err(_) => {
+                        self.error(
+                            start..start + token.len(),
+                            format!("Invalid run '{}'", token),
+                            "expected `count*value`",
+                        );
+                        continue;
+                    }
+                },
+                None => (1, token),
+            };
+            if !self.fits(
+                start..start + token.len(),
+                words_read.len().saturating_add(count),
+            ) {
+                break;
+            }
+            let value = self.value(value, offset(&src, value), 16);
+            words_read.extend(std::iter::repeat_n(value, count));
+        }
+        words_read
+    }
+}
+
+/// Sets a word, filling any gap before it with zeros.
+fn store(words: &mut Vec<u16>, address: usize, value: u16) {
+    if words.len() <= address {
+        words.resize(address + 1, 0);
+    }
+    words[address] = value;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use clap::ValueEnum;
+
+    use super::Format;
+
+    fn read(format: Format, src: &str) -> Result<Vec<u16>, Vec<&'static str>> {
+        let file: Rc<str> = "test".into();
+        format
+            .read(src.as_bytes(), &file)
+            .map_err(|errors| errors.iter().map(|e| e.code).collect())
+    }
+
+    #[test]
+    fn round_trip() {
+        let words: Vec<u16> = (0..20u16)
+            .map(|i| i.wrapping_mul(0x1357) ^ 0xA5A5)
+            .collect();
+        let file: Rc<str> = "test".into();
+        for format in Format::value_variants() {
+            let mut out = Vec::new();
+            format.write(&words, &mut out).unwrap();
+            assert_eq!(format.read(&out, &file).unwrap(), words, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn beyond_rom() {
+        let invalid = Err(vec!["invalid-binary"]);
+        assert_eq!(read(Format::Logisim, "v2.0 raw\n99999999999*0\n"), invalid);
+        assert_eq!(read(Format::Logisim, "v2.0 raw\n32769*1\n"), invalid);
+        assert_eq!(
+            read(Format::Mif, "CONTENT BEGIN\n[0..FFFFFFFF] : 0;\nEND;\n"),
+            invalid
+        );
+        assert_eq!(
+            read(
+                Format::Mif,
+                "CONTENT BEGIN\n[0..FFFFFFFFFFFFFFFF] : 0;\nEND;\n"
+            ),
+            invalid
+        );
+        assert_eq!(read(Format::Readmemh, "@FFFFFFFF 0000\n"), invalid);
+        assert_eq!(
+            read(Format::IntelHex, ":02000004FFFFFC\n:020000000000FE\n"),
+            invalid
+        );
+        let hack = "0000000000000000\n".repeat(40000);
+        assert_eq!(read(Format::Hack, &hack), invalid);
+        let file: Rc<str> = "test".into();
+        for format in [Format::RawBigEndian, Format::RawLittleEndian] {
+            let errors = format.read(&[0; 2 * 32769], &file).unwrap_err();
+            assert_eq!(errors[0].code, "invalid-binary");
+        }
+    }
+
+    #[test]
+    fn fills_rom() {
+        assert_eq!(
+            read(Format::Logisim, "v2.0 raw\n32768*1\n").map(|w| w.len()),
+            Ok(32768)
+        );
+        assert_eq!(
+            read(Format::Logisim, "v2.0 raw\n32767*1 2\n").map(|w| w.len()),
+            Ok(32768)
+        );
+        assert_eq!(
+            read(Format::Readmemh, "@7FFF 0001\n").map(|w| w.len()),
+            Ok(32768)
+        );
+        let hack = "0000000000000000\n".repeat(32768);
+        assert_eq!(read(Format::Hack, &hack).map(|w| w.len()), Ok(32768));
+        let file: Rc<str> = "test".into();
+        let words = Format::RawBigEndian.read(&[0; 2 * 32768], &file).unwrap();
+        assert_eq!(words.len(), 32768);
+    }
+}
@@ -0,0 +1,42 @@
+use std::{
+    fs::{File, read},
+    io::BufWriter,
+    path::Path,
+    rc::Rc,
+};
+
+pub use format::Format;
+
+use crate::diagnostic::Diagnostic;
+
+mod format;
+
+pub struct Hex {
+    pub instructions: Vec<u16>,
+}
+
+impl Hex {
+    /// Reads a program in the format its extension names, see [`Format::detect`].
+    pub fn from_file(path: &Path) -> Result<Self, Vec<Diagnostic>> {
+        let src = read(path).map_err(|e| Diagnostic::io(path, e))?;
+        let extension = path.extension().unwrap_or_default().to_string_lossy();
+        let format = Format::detect(&extension, &src).ok_or_else(|| {
+            Diagnostic::error(
+                "unknown-filetype",
+                format!("Unknown binary format '.{}'", extension),
+            )
+        })?;
+        Hex::read(path, &src, format)
+    }
+
+    pub fn read(path: &Path, src: &[u8], format: Format) -> Result<Self, Vec<Diagnostic>> {
+        let file: Rc<str> = path.to_string_lossy().into();
+        let instructions = format.read(src, &file)?;
+        Ok(Hex { instructions })
+    }
+
+    pub fn write(&self, path: &Path, format: Format) -> std::io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        format.write(&self.instructions, &mut out)
+    }
+}
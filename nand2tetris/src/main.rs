@@ -1,30 +1,210 @@
-use std::{ffi::OsStr, fmt::Display, path::PathBuf, process::exit};
+use std::{
+    ffi::OsStr,
+    fmt::Display,
+    fs::{File, read_dir},
+    ops::Range,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
 
-use assembly::Assembly;
-use clap::Parser;
-use hex::Hex;
-use vm::VM;
+use assembly::{Assembly, disassembler::Disassembly, linker, object::Object};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use cpu::{Cpu, MEMORY_SIZE};
+use diagnostic::{Diagnostic, Severity};
+use hex::{Format, Hex};
+use memory::MemoryMap;
+use symbols::SymbolTable;
+use tst::TestScript;
+use vm::{Lowering, Routine, VM};
 
 pub mod assembly;
+pub mod cpu;
+pub mod diagnostic;
 pub mod hex;
+pub mod memory;
+pub mod symbols;
+pub mod tst;
 pub mod vm;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
-struct Args {
-    file: PathBuf,
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    /// Treat warnings as errors
+    #[arg(long, global = true)]
+    deny_warnings: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Assemble a .asm file into a .hack file
+    Assemble {
+        input: PathBuf,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Translate a .vm file or a directory of .vm files into assembly
+    Translate {
+        input: PathBuf,
+
+        /// The last stage to produce
+        #[arg(long, value_enum, default_value_t = FileType::Assembly)]
+        emit: FileType,
+
+        #[command(flatten)]
+        output: OutputArgs,
+
+        #[command(flatten)]
+        vm: VmArgs,
+    },
+    /// Build any supported input down to the requested stage
+    Compile {
+        input: PathBuf,
+
+        /// The last stage to produce
+        #[arg(long, value_enum, default_value_t = FileType::Hack)]
+        emit: FileType,
+
+        #[command(flatten)]
+        output: OutputArgs,
+
+        #[command(flatten)]
+        vm: VmArgs,
+    },
+    /// Run a program on the CPU emulator and print memory afterwards
+    Run {
+        input: PathBuf,
+
+        /// Number of instructions to execute
+        #[arg(long, default_value_t = 1_000_000)]
+        cycles: u64,
+
+        /// Set RAM before running, e.g. `--set 0=256`
+        #[arg(long = "set", value_parser = parse_assignment)]
+        set: Vec<(u16, u16)>,
+
+        /// RAM addresses to print afterwards, e.g. `--print 0` or `--print 256..260`
+        #[arg(long = "print", value_parser = parse_range, default_value = "0..16")]
+        print: Vec<Range<u16>>,
+
+        #[command(flatten)]
+        vm: VmArgs,
+    },
+    /// Run a CPU emulator test script (.tst) and compare its output
+    Test {
+        script: PathBuf,
+
+        #[command(flatten)]
+        vm: VmArgs,
+    },
+    /// Link separately built programs into a single .hack file
+    Link {
+        /// Object (.obj) files, or anything that can be built into one
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+
+        /// Write the program to this file instead of next to the first input
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+
+        /// Also write a memory map (.map) showing how ROM and RAM are used
+        #[arg(long)]
+        map: bool,
+
+        /// Format of the machine code
+        #[arg(long, value_enum, default_value_t = Format::Hack)]
+        format: Format,
+    },
+    /// Disassemble a program back into Hack assembly
+    Disasm {
+        input: PathBuf,
+
+        /// Write the assembly to this file instead of stdout
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+
+        /// Name labels and variables after this symbol table (.sym)
+        #[arg(long)]
+        symbols: Option<PathBuf>,
+    },
+}
+
+#[derive(Args, Debug)]
+struct OutputArgs {
+    /// Write the final output to this file
+    #[arg(short, long, conflicts_with = "out_dir")]
+    out: Option<PathBuf>,
+
+    /// Write all outputs into this directory
+    #[arg(long)]
+    out_dir: Option<PathBuf>,
+
+    /// Also write a listing (.lst) with the address and encoding of every instruction
+    #[arg(long)]
+    listing: bool,
+
+    /// Also write the symbol table (.sym) for emulators and debuggers
+    #[arg(long)]
+    symbols: bool,
+
+    /// Also write a memory map (.map) showing how ROM and RAM are used
+    #[arg(long)]
+    map: bool,
+
+    /// Format of the machine code
+    #[arg(long, value_enum, default_value_t = Format::Hack)]
+    format: Format,
 }
 
+#[derive(Args, Debug, Default)]
+struct VmArgs {
+    /// Emit bootstrap code that sets up the stack and calls Sys.init (default for directories)
+    #[arg(long, overrides_with = "no_bootstrap")]
+    bootstrap: bool,
+
+    /// Do not emit bootstrap code (default for single .vm files)
+    #[arg(long)]
+    no_bootstrap: bool,
+
+    /// Optimize VM code before translating it, and the assembly it is translated into
+    #[arg(short = 'O', long)]
+    optimize: bool,
+
+    /// Emit these constructs once as routines shared by the whole program, instead of inline
+    #[arg(long, value_enum, value_delimiter = ',')]
+    shared: Vec<Routine>,
+
+    /// How VM statements are translated into assembly
+    #[arg(long, value_enum, default_value_t = Lowering::Stack)]
+    lowering: Lowering,
+
+    /// Print the syntax tree of every .vm file after parsing it
+    #[arg(long)]
+    dump_ast: bool,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 enum FileType {
-    ASSEMBLY,
+    #[value(name = "vm")]
     VM,
+    #[value(name = "asm")]
+    Assembly,
+    #[value(name = "obj")]
+    Object,
+    #[value(name = "hack")]
+    Hack,
 }
 
 impl Display for FileType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            FileType::ASSEMBLY => f.write_str("asm"),
+            FileType::Assembly => f.write_str("asm"),
             FileType::VM => f.write_str("vm"),
+            FileType::Object => f.write_str("obj"),
+            FileType::Hack => f.write_str("hack"),
         }
     }
 }
@@ -34,69 +214,457 @@ impl TryFrom<&OsStr> for FileType {
 
     fn try_from(value: &OsStr) -> Result<Self, Self::Error> {
         match value.to_str().unwrap_or_default() {
-            "asm" => Ok(FileType::ASSEMBLY),
+            "asm" => Ok(FileType::Assembly),
             "vm" => Ok(FileType::VM),
+            "obj" => Ok(FileType::Object),
+            "hack" => Ok(FileType::Hack),
+            extension
+                if Format::value_variants()
+                    .iter()
+                    .any(|f| f.extension() == extension) =>
+            {
+                Ok(FileType::Hack)
+            }
             _ => Err("Filetype not recognized"),
         }
     }
 }
 
-fn main() {
-    let args = Args::parse();
-    if !args.file.is_file() {
-        println!(
-            "File {} does not exist or is not a file",
-            args.file.to_string_lossy()
-        );
-        exit(1);
+/// A RAM address, which has to be below [`MEMORY_SIZE`].
+fn parse_address(value: &str) -> Result<u16, String> {
+    let address: u16 = value.parse().map_err(|e| format!("{e}"))?;
+    if address as usize >= MEMORY_SIZE {
+        return Err(format!(
+            "RAM address {} is out of range, the last one is {}",
+            address,
+            MEMORY_SIZE - 1
+        ));
     }
-    let filetype = match FileType::try_from(args.file.extension().unwrap_or_default()) {
-        Ok(v) => v,
-        Err(e) => {
-            println!("Error getting filetype: {e}");
-            exit(1);
+    Ok(address)
+}
+
+fn parse_assignment(value: &str) -> Result<(u16, u16), String> {
+    let (address, data) = value
+        .split_once('=')
+        .ok_or("Expected ADDRESS=VALUE".to_string())?;
+    let address = parse_address(address)?;
+    let data = data
+        .parse::<i16>()
+        .map(|v| v as u16)
+        .or_else(|_| data.parse::<u16>())
+        .map_err(|e| format!("{e}"))?;
+    Ok((address, data))
+}
+
+fn parse_range(value: &str) -> Result<Range<u16>, String> {
+    match value.split_once("..") {
+        Some((start, end)) => {
+            let start = parse_address(start)?;
+            // The end is exclusive, so it may be one past the last address
+            let end: u16 = end.parse().map_err(|e| format!("{e}"))?;
+            if end as usize > MEMORY_SIZE {
+                return Err(format!(
+                    "RAM address {} is out of range, the last one is {}",
+                    end - 1,
+                    MEMORY_SIZE - 1
+                ));
+            }
+            Ok(start..end)
+        }
+        None => {
+            let address = parse_address(value)?;
+            Ok(address..address + 1)
         }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Command::Assemble { input, output } => build(
+            &input,
+            FileType::Hack,
+            &output,
+            &VmArgs::default(),
+            cli.deny_warnings,
+        ),
+        Command::Translate {
+            input,
+            emit,
+            output,
+            vm,
+        }
+        | Command::Compile {
+            input,
+            emit,
+            output,
+            vm,
+        } => build(&input, emit, &output, &vm, cli.deny_warnings),
+        Command::Run {
+            input,
+            cycles,
+            set,
+            print,
+            vm,
+        } => run(&input, cycles, &set, &print, &vm),
+        Command::Test { script, vm } => test(&script, &vm),
+        Command::Link {
+            inputs,
+            out,
+            map,
+            format,
+        } => link(&inputs, out, map, format, cli.deny_warnings),
+        Command::Disasm {
+            input,
+            out,
+            symbols,
+        } => disasm(
+            &input,
+            out.as_deref(),
+            symbols.as_deref(),
+            cli.deny_warnings,
+        ),
     };
-    println!(
-        "Compiling {} of type {}",
-        args.file.to_string_lossy(),
-        filetype
-    );
-    let code = load_file(&args.file, filetype).unwrap();
-    let basepath = args.file.clone().with_extension("");
-    code.compile(basepath).unwrap();
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            diagnostic::print(&e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+impl OutputArgs {
+    /// The path to write the output of the given stage to.
+    fn path(&self, input: &Path, filetype: FileType, last: bool) -> Result<PathBuf, Diagnostic> {
+        match &self.out {
+            Some(out) if last => Ok(out.clone()),
+            _ if filetype == FileType::Hack => self.side_path(input, self.format.extension()),
+            _ => self.side_path(input, &filetype.to_string()),
+        }
+    }
+
+    /// The path for an output that is not a stage of its own, such as a listing.
+    fn side_path(&self, input: &Path, extension: &str) -> Result<PathBuf, Diagnostic> {
+        if let Some(out) = &self.out {
+            return Ok(out.with_extension(extension));
+        }
+        let name = if input.is_dir() {
+            let dir = input.canonicalize().map_err(|e| Diagnostic::io(input, e))?;
+            PathBuf::from(dir.file_name().unwrap_or_default())
+        } else {
+            PathBuf::from(input.file_stem().unwrap_or_default())
+        };
+        let base = match &self.out_dir {
+            Some(dir) => dir.join(name),
+            None if input.is_dir() => input.join(name),
+            None => input.with_file_name(name),
+        };
+        Ok(base.with_extension(extension))
+    }
 }
 
 pub enum CodeType {
     VM(VM),
     Assembly(Assembly),
+    Object(Object),
     Hex(Hex),
 }
 
-fn load_file(file: &PathBuf, filetype: FileType) -> Result<CodeType, String> {
-    match filetype {
-        FileType::ASSEMBLY => Ok(CodeType::Assembly(Assembly::from_file(file)?)),
-        FileType::VM => Ok(CodeType::VM(VM::from_file(file)?)),
+fn load(input: &Path, vm_args: &VmArgs) -> Result<CodeType, Vec<Diagnostic>> {
+    let mut code = if input.is_dir() {
+        CodeType::VM(VM::from_dir(input)?)
+    } else if input.is_file() {
+        let filetype = FileType::try_from(input.extension().unwrap_or_default()).map_err(|e| {
+            Diagnostic::error("unknown-filetype", e)
+                .with_note("supported inputs are .asm, .vm, .obj, .hack and other machine code files or directories")
+        })?;
+        match filetype {
+            FileType::Assembly => CodeType::Assembly(Assembly::from_file(input)?),
+            FileType::VM => CodeType::VM(VM::from_file(input)?),
+            FileType::Object => CodeType::Object(Object::from_file(input)?),
+            FileType::Hack => CodeType::Hex(Hex::from_file(input)?),
+        }
+    } else {
+        return Err(Diagnostic::error(
+            "io",
+            format!("File {} does not exist", input.to_string_lossy()),
+        )
+        .into());
+    };
+    if let CodeType::VM(vm) = &mut code
+        && (vm_args.bootstrap || vm_args.no_bootstrap)
+    {
+        vm.set_bootstrap(vm_args.bootstrap);
     }
+    if let CodeType::VM(vm) = &mut code {
+        vm.set_optimize(vm_args.optimize);
+        vm.set_shared(vm_args.shared.clone());
+        vm.set_lowering(vm_args.lowering);
+        if vm_args.dump_ast {
+            print!("{}", vm.dump_ast());
+        }
+    }
+    Ok(code)
 }
 
-impl CodeType {
-    fn compile(self, basepath: PathBuf) -> Result<(), String> {
-        let out = match self {
-            CodeType::VM(v) => v.compile()?,
-            CodeType::Assembly(v) => v.compile()?,
-            CodeType::Hex(_) => return Ok(()),
+/// Prints warnings, or fails with them if warnings are denied.
+fn warn(warnings: Vec<Diagnostic>, deny: bool) -> Result<(), Vec<Diagnostic>> {
+    if deny && !warnings.is_empty() {
+        return Err(warnings
+            .into_iter()
+            .map(|mut w| {
+                w.severity = Severity::Error;
+                w.with_note("warnings are denied by --deny-warnings")
+            })
+            .collect());
+    }
+    diagnostic::print(&warnings);
+    Ok(())
+}
+
+fn build(
+    input: &Path,
+    emit: FileType,
+    output: &OutputArgs,
+    vm_args: &VmArgs,
+    deny_warnings: bool,
+) -> Result<(), Vec<Diagnostic>> {
+    let mut code = load(input, vm_args)?;
+    // Only lint assembly written by hand, generated code is checked where it is generated
+    if let CodeType::Assembly(assembly) = &code {
+        warn(assembly.lint(emit > FileType::Object), deny_warnings)?;
+    }
+    if code.filetype() >= emit {
+        return Err(Diagnostic::error(
+            "nothing-to-emit",
+            format!(
+                "Input {} is already of type {}, nothing to emit for {}",
+                input.to_string_lossy(),
+                code.filetype(),
+                emit
+            ),
+        )
+        .into());
+    }
+    if let Some(dir) = &output.out_dir {
+        std::fs::create_dir_all(dir).map_err(|e| Diagnostic::io(dir, e))?;
+    }
+    while code.filetype() < emit {
+        if output.listing
+            && let CodeType::Assembly(assembly) = &code
+        {
+            let path = output.side_path(input, "lst")?;
+            assembly.write_listing(&path)?;
+            println!("Written listing to {}", path.to_string_lossy());
+        }
+        if output.symbols
+            && let CodeType::Assembly(assembly) = &code
+        {
+            let path = output.side_path(input, "sym")?;
+            assembly.symbols()?.write(&path)?;
+            println!("Written symbols to {}", path.to_string_lossy());
+        }
+        // Memory is allocated once the program is linked, objects do not know it yet
+        if let CodeType::Assembly(assembly) = &code
+            && emit > FileType::Object
+        {
+            let map = assembly.memory_map()?;
+            warn(map.check(), deny_warnings)?;
+            if output.map {
+                let path = output.side_path(input, "map")?;
+                map.write(&path)?;
+                println!("Written memory map to {}", path.to_string_lossy());
+            }
+        }
+        code = match code {
+            CodeType::VM(vm) => {
+                let (code, report) = vm.compile()?;
+                if let Some(report) = report {
+                    print!("{}", report);
+                }
+                code
+            }
+            code => code.compile()?,
         };
-        out.write(&basepath);
-        out.compile(basepath)?;
-        Ok(())
+        // Objects are only kept when asked for, otherwise they are linked right away
+        if code.filetype() == FileType::Object && emit != FileType::Object {
+            continue;
+        }
+        let path = output.path(input, code.filetype(), code.filetype() == emit)?;
+        code.write(&path, output.format)?;
+        println!("Written output to {}", path.to_string_lossy());
+    }
+    Ok(())
+}
+
+fn load_hex(input: &Path, vm_args: &VmArgs) -> Result<Hex, Vec<Diagnostic>> {
+    let mut code = load(input, vm_args)?;
+    loop {
+        match code {
+            CodeType::Hex(hex) => return Ok(hex),
+            _ => code = code.compile()?,
+        }
+    }
+}
+
+fn run(
+    input: &Path,
+    cycles: u64,
+    set: &[(u16, u16)],
+    print: &[Range<u16>],
+    vm_args: &VmArgs,
+) -> Result<(), Vec<Diagnostic>> {
+    let hex = load_hex(input, vm_args)?;
+    let mut cpu = Cpu::new(&hex);
+    for (address, value) in set {
+        cpu.ram[*address as usize] = *value;
+    }
+    cpu.run(cycles);
+    println!("PC = {}, A = {}, D = {}", cpu.pc, cpu.a, cpu.d as i16);
+    for address in print.iter().cloned().flatten() {
+        println!("RAM[{}] = {}", address, cpu.ram[address as usize] as i16);
+    }
+    Ok(())
+}
+
+fn contains_vm_files(dir: &Path) -> bool {
+    read_dir(dir).is_ok_and(|entries| {
+        entries
+            .flatten()
+            .any(|e| e.path().extension().is_some_and(|e| e == "vm"))
+    })
+}
+
+fn test(script: &Path, vm_args: &VmArgs) -> Result<(), Vec<Diagnostic>> {
+    let script = TestScript::from_file(script)?;
+    // Build from the VM sources where possible so a stale .asm file is never tested
+    script.run(&|path: &Path| {
+        let vm = path.with_extension("vm");
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let source = if vm.is_file() {
+            vm
+        } else if contains_vm_files(dir) {
+            dir.to_path_buf()
+        } else {
+            path.to_path_buf()
+        };
+        load_hex(&source, vm_args)
+    })?;
+    println!("Test passed");
+    Ok(())
+}
+
+fn link(
+    inputs: &[PathBuf],
+    out: Option<PathBuf>,
+    map: bool,
+    format: Format,
+    deny_warnings: bool,
+) -> Result<(), Vec<Diagnostic>> {
+    let mut objects = Vec::new();
+    let mut errors = Vec::new();
+    'inputs: for input in inputs {
+        let mut code = match load(input, &VmArgs::default()) {
+            Ok(code) => code,
+            Err(e) => {
+                errors.extend(e);
+                continue;
+            }
+        };
+        while code.filetype() < FileType::Object {
+            code = match code.compile() {
+                Ok(code) => code,
+                Err(e) => {
+                    errors.extend(e);
+                    continue 'inputs;
+                }
+            };
+        }
+        match code {
+            CodeType::Object(object) => objects.push(object),
+            _ => errors.push(Diagnostic::error(
+                "nothing-to-emit",
+                format!("Input {} is already linked", input.to_string_lossy()),
+            )),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let (hex, symbols) = linker::link(&objects)?;
+    let memory = MemoryMap::new(hex.instructions.len(), symbols);
+    warn(memory.check(), deny_warnings)?;
+    let path = out.unwrap_or_else(|| inputs[0].with_extension(format.extension()));
+    if map {
+        let path = path.with_extension("map");
+        memory.write(&path)?;
+        println!("Written memory map to {}", path.to_string_lossy());
+    }
+    hex.write(&path, format)
+        .map_err(|e| Diagnostic::io(&path, e))?;
+    println!("Written output to {}", path.to_string_lossy());
+    Ok(())
+}
+
+fn disasm(
+    input: &Path,
+    out: Option<&Path>,
+    symbols: Option<&Path>,
+    deny_warnings: bool,
+) -> Result<(), Vec<Diagnostic>> {
+    let hex = load_hex(input, &VmArgs::default())?;
+    let symbols = match symbols {
+        Some(path) => SymbolTable::from_file(path)?,
+        None => SymbolTable::default(),
+    };
+    let disassembly = Disassembly::new(&hex, &symbols);
+    warn(disassembly.diagnostics(), deny_warnings)?;
+    match out {
+        Some(path) => {
+            let mut file = File::create(path).map_err(|e| Diagnostic::io(path, e))?;
+            disassembly
+                .write(&mut file)
+                .map_err(|e| Diagnostic::io(path, e))?;
+            println!("Written output to {}", path.to_string_lossy());
+        }
+        None => disassembly
+            .write(&mut std::io::stdout().lock())
+            .map_err(|e| Diagnostic::io(Path::new("<stdout>"), e))?,
+    }
+    Ok(())
+}
+
+impl CodeType {
+    fn filetype(&self) -> FileType {
+        match self {
+            CodeType::VM(_) => FileType::VM,
+            CodeType::Assembly(_) => FileType::Assembly,
+            CodeType::Object(_) => FileType::Object,
+            CodeType::Hex(_) => FileType::Hack,
+        }
+    }
+
+    fn compile(self) -> Result<CodeType, Vec<Diagnostic>> {
+        match self {
+            CodeType::VM(v) => v.compile().map(|(code, _)| code),
+            CodeType::Assembly(v) => v.compile(),
+            CodeType::Object(v) => Ok(CodeType::Hex(linker::link(&[v])?.0)),
+            CodeType::Hex(_) => Err(Diagnostic::error(
+                "nothing-to-emit",
+                "Hack binaries can not be compiled any further",
+            )
+            .into()),
+        }
     }
 
-    fn write(&self, basepath: &PathBuf) {
+    fn write(&self, path: &Path, format: Format) -> Result<(), Diagnostic> {
         match self {
-            CodeType::VM(_) => (),
-            CodeType::Assembly(_) => (),
-            CodeType::Hex(v) => v.write(basepath.clone()),
+            CodeType::VM(_) => Ok(()),
+            CodeType::Assembly(v) => v.write(path),
+            CodeType::Object(v) => v.write(path),
+            CodeType::Hex(v) => v.write(path, format),
         }
+        .map_err(|e| Diagnostic::io(path, e))
     }
 }
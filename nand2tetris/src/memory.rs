@@ -0,0 +1,243 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use crate::{
+    assembly::ROM_SIZE,
+    diagnostic::Diagnostic,
+    symbols::{STATIC_PREFIX, SymbolKind, SymbolTable},
+};
+
+/// First RAM address the assembler allocates variables at, after R0 to R15.
+pub const VARIABLES_START: u16 = 16;
+/// Where the stack starts, and so where variables have to end.
+pub const STACK_START: u16 = 256;
+/// Where the heap starts, and so where the stack has to end.
+pub const HEAP_START: u16 = 2048;
+/// Number of slots the static segment has, shared by all VM files.
+pub const STATIC_SLOTS: usize = (STACK_START - VARIABLES_START) as usize;
+
+/// The regions of the Hack memory map, by their first address.
+const REGIONS: [(u16, u16, &str); 6] = [
+    (0, 15, "registers R0-R15"),
+    (VARIABLES_START, STACK_START - 1, "variables and statics"),
+    (STACK_START, HEAP_START - 1, "stack"),
+    (HEAP_START, 0x3FFF, "heap"),
+    (0x4000, 0x5FFF, "screen"),
+    (0x6000, 0x6000, "keyboard"),
+];
+
+/// How a linked program uses ROM and RAM.
+#[derive(Debug)]
+pub struct MemoryMap {
+    /// Number of words of ROM the program takes
+    pub rom: usize,
+    pub symbols: SymbolTable,
+}
+
+impl MemoryMap {
+    pub fn new(rom: usize, symbols: SymbolTable) -> Self {
+        MemoryMap { rom, symbols }
+    }
+
+    /// Variables and statics, ordered by address.
+    fn variables(&self) -> Vec<(&str, u16)> {
+        let mut variables: Vec<_> = self
+            .symbols
+            .symbols
+            .iter()
+            .filter(|s| matches!(s.kind, SymbolKind::Variable | SymbolKind::Static))
+            .map(|s| (s.name.as_str(), s.value))
+            .collect();
+        variables.sort_by_key(|(_, value)| *value);
+        variables
+    }
+
+    /// Number of statics each VM file uses, by file name.
+    fn statics(&self) -> BTreeMap<&str, usize> {
+        let mut statics = BTreeMap::new();
+        for symbol in &self.symbols.symbols {
+            if let Some(name) = symbol.name.strip_prefix(STATIC_PREFIX)
+                && let Some((file, _)) = name.rsplit_once('.')
+            {
+                *statics.entry(file).or_default() += 1;
+            }
+        }
+        statics
+    }
+
+    /// Warns about variables that were allocated in the stack or beyond.
+    pub fn check(&self) -> Vec<Diagnostic> {
+        let variables = self.variables();
+        let overflow: Vec<_> = variables
+            .iter()
+            .filter(|(_, value)| *value >= STACK_START)
+            .collect();
+        let Some((first, address)) = overflow.first() else {
+            return Vec::new();
+        };
+        let mut warning = Diagnostic::warning(
+            "ram-overflow",
+            format!(
+                "{} variables and statics do not fit below the stack at {}",
+                overflow.len(),
+                STACK_START
+            ),
+        )
+        .with_note(format!(
+            "'{}' is the first to be allocated at {}, where the stack overwrites it",
+            first, address
+        ))
+        .with_note(format!(
+            "{} variables and statics are used in total, only {} fit",
+            variables.len(),
+            STATIC_SLOTS
+        ));
+        if let Some((name, address)) = variables.iter().find(|(_, value)| *value >= 0x4000) {
+            warning = warning.with_note(format!(
+                "'{}' is even allocated at {}, in memory mapped I/O",
+                name, address
+            ));
+        }
+        vec![warning]
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Diagnostic> {
+        let file = File::create(path).map_err(|e| Diagnostic::io(path, e))?;
+        let mut out = BufWriter::new(file);
+        self.write_to(&mut out)
+            .and_then(|_| out.flush())
+            .map_err(|e| Diagnostic::io(path, e))
+    }
+
+    fn write_to(&self, out: &mut impl Write) -> std::io::Result<()> {
+        writeln!(
+            out,
+            "ROM: {} of {} words used ({:.1}%)",
+            self.rom,
+            ROM_SIZE,
+            self.rom as f64 * 100.0 / ROM_SIZE as f64
+        )?;
+
+        let variables = self.variables();
+        writeln!(out, "\nRAM:")?;
+        for (start, end, name) in REGIONS {
+            let used = variables
+                .iter()
+                .filter(|(_, value)| (start..=end).contains(value))
+                .count();
+            let range = match start == end {
+                true => format!("{}", start),
+                false => format!("{}-{}", start, end),
+            };
+            match used {
+                0 => writeln!(out, "  {:<12} {}", range, name)?,
+                _ => writeln!(out, "  {:<12} {:<24} {} allocated", range, name, used)?,
+            }
+        }
+
+        let statics = self.statics();
+        if !statics.is_empty() {
+            writeln!(out, "\nStatics per VM file:")?;
+            for (file, count) in &statics {
+                writeln!(out, "  {:<32} {:>5}", file, count)?;
+            }
+            writeln!(
+                out,
+                "  {:<32} {:>5} of {}",
+                "total",
+                statics.values().sum::<usize>(),
+                STATIC_SLOTS
+            )?;
+        }
+
+        if !variables.is_empty() {
+            writeln!(out, "\nVariables:")?;
+            for (name, value) in variables {
+                writeln!(out, "  {:<32} {:>5}  {:04X}", name, value, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{
+        assembly::{Assembly, ROM_SIZE, linker::link},
+        diagnostic::Diagnostic,
+    };
+
+    use super::{MemoryMap, STATIC_SLOTS};
+
+    fn link_sources(sources: &[String]) -> Result<MemoryMap, Vec<Diagnostic>> {
+        let objects = sources
+            .iter()
+            .map(|src| {
+                Assembly::from_source(src, Path::new("test.asm"))
+                    .unwrap()
+                    .object()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let (hex, symbols) = link(&objects)?;
+        Ok(MemoryMap::new(hex.instructions.len(), symbols))
+    }
+
+    /// A program using the first `count` statics of a VM file.
+    fn statics(file: &str, count: usize) -> String {
+        (0..count)
+            .map(|i| format!("@staticvar.{}.{}\nM=0\n", file, i))
+            .collect()
+    }
+
+    #[test]
+    fn rom_overflow() {
+        let half = "D=A\n".repeat(ROM_SIZE / 2);
+        assert!(link_sources(&[half.clone(), half.clone()]).is_ok());
+
+        let errors = link_sources(&[half.clone(), half + "D=A\n"]).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, "rom-overflow");
+        assert_eq!(
+            errors[0].message,
+            "The program takes 32769 words, but ROM only holds 32768"
+        );
+
+        let errors = link_sources(&["D=A\n".repeat(ROM_SIZE + 1)]).unwrap_err();
+        assert_eq!(errors[0].code, "rom-overflow");
+    }
+
+    #[test]
+    fn statics_into_stack() {
+        let map = link_sources(&[statics("Main", 200), statics("Sys", 40)]).unwrap();
+        assert!(map.check().is_empty());
+
+        let map = link_sources(&[statics("Main", 200), statics("Sys", 41)]).unwrap();
+        let warnings = map.check();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].code, "ram-overflow");
+        assert_eq!(
+            warnings[0].notes[0],
+            "'staticvar.Sys.40' is the first to be allocated at 256, where the stack overwrites it"
+        );
+    }
+
+    #[test]
+    fn statics_per_file() {
+        let map = link_sources(&[statics("Main", 3), statics("Sys", 2) + "@i\nM=0\n"]).unwrap();
+        let mut out = Vec::new();
+        map.write_to(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let expected = format!(
+            "\nStatics per VM file:\n  {:<32} {:>5}\n  {:<32} {:>5}\n  {:<32} {:>5} of {}\n",
+            "Main", 3, "Sys", 2, "total", 5, STATIC_SLOTS
+        );
+        assert!(out.contains(&expected), "{}", out);
+        assert!(out.contains("  16-255       variables and statics    6 allocated\n"));
+    }
+}
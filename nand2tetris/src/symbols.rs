@@ -0,0 +1,242 @@
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+    rc::Rc,
+};
+
+use crate::diagnostic::{Diagnostic, Location};
+
+/// Prefix of the labels the VM translator puts at function entry points.
+pub const FUNCTION_PREFIX: &str = "function:";
+/// Prefix of the variables the VM translator allocates for the static segment.
+pub const STATIC_PREFIX: &str = "staticvar.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SymbolKind {
+    /// A label marking a ROM address
+    Label,
+    /// The entry point of a VM function
+    Function,
+    /// A RAM address allocated on first use
+    Variable,
+    /// A variable backing the static segment of a VM file
+    Static,
+    Predefined,
+}
+
+impl SymbolKind {
+    /// The kind of a label defined in assembly.
+    pub fn label(name: &str) -> Self {
+        match name.starts_with(FUNCTION_PREFIX) {
+            true => SymbolKind::Function,
+            false => SymbolKind::Label,
+        }
+    }
+
+    /// The kind of a variable allocated by the assembler.
+    pub fn variable(name: &str) -> Self {
+        match name.starts_with(STATIC_PREFIX) {
+            true => SymbolKind::Static,
+            false => SymbolKind::Variable,
+        }
+    }
+
+    /// Whether the symbol is an address in ROM rather than RAM.
+    pub fn is_rom(&self) -> bool {
+        matches!(self, SymbolKind::Label | SymbolKind::Function)
+    }
+}
+
+impl Display for SymbolKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SymbolKind::Label => "label",
+            SymbolKind::Function => "function",
+            SymbolKind::Variable => "variable",
+            SymbolKind::Static => "static",
+            SymbolKind::Predefined => "predefined",
+        })
+    }
+}
+
+impl TryFrom<&str> for SymbolKind {
+    type Error = Diagnostic;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "label" => Ok(SymbolKind::Label),
+            "function" => Ok(SymbolKind::Function),
+            "variable" => Ok(SymbolKind::Variable),
+            "static" => Ok(SymbolKind::Static),
+            "predefined" => Ok(SymbolKind::Predefined),
+            _ => Err(Diagnostic::error(
+                "invalid-symbol",
+                format!("Unknown symbol kind '{}'", value),
+            )
+            .with_note("kinds are label, function, variable, static and predefined")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u16,
+    pub kind: SymbolKind,
+}
+
+/// The symbols of an assembled program, stored in `.sym` files as `kind value name` lines.
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    pub symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn from_file(path: &Path) -> Result<Self, Vec<Diagnostic>> {
+        let mut src = String::new();
+        File::open(path)
+            .map_err(|e| Diagnostic::io(path, e))?
+            .read_to_string(&mut src)
+            .map_err(|e| Diagnostic::io(path, e))?;
+        let file: Rc<str> = path.to_string_lossy().into();
+
+        let mut symbols = Vec::new();
+        let mut errors = Vec::new();
+        let mut offset = 0;
+        for line in src.split_inclusive('\n') {
+            let content = line.trim();
+            let start = offset + (line.len() - line.trim_start().len());
+            let location = Location::new(&file, start..start + content.len());
+            offset += line.len();
+            if content.is_empty() || content.starts_with("//") {
+                continue;
+            }
+            let mut parts = content.split_whitespace();
+            let symbol = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some(kind), Some(value), Some(name), None) => {
+                    SymbolKind::try_from(kind).and_then(|kind| {
+                        let value = value.parse().map_err(|_| {
+                            Diagnostic::error(
+                                "invalid-symbol",
+                                format!("Invalid address '{}'", value),
+                            )
+                        })?;
+                        Ok(Symbol {
+                            name: name.to_string(),
+                            value,
+                            kind,
+                        })
+                    })
+                }
+                _ => Err(Diagnostic::error("invalid-symbol", "Invalid symbol")
+                    .with_label("expected `kind value name`")),
+            };
+            match symbol {
+                Ok(symbol) => symbols.push(symbol),
+                Err(e) => errors.push(e.with_location(location)),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(SymbolTable { symbols })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Diagnostic> {
+        let mut file = File::create(path).map_err(|e| Diagnostic::io(path, e))?;
+        let mut content = String::new();
+        for symbol in &self.symbols {
+            content += &format!("{} {} {}\n", symbol.kind, symbol.value, symbol.name);
+        }
+        file.write_all(content.as_bytes())
+            .map_err(|e| Diagnostic::io(path, e))
+    }
+
+    /// The first ROM label at the address.
+    pub fn label(&self, address: u16) -> Option<&str> {
+        self.find(address, true)
+    }
+
+    /// The first RAM variable at the address.
+    pub fn variable(&self, address: u16) -> Option<&str> {
+        self.find(address, false)
+    }
+
+    fn find(&self, address: u16, rom: bool) -> Option<&str> {
+        self.symbols
+            .iter()
+            .find(|s| s.value == address && s.kind.is_rom() == rom)
+            .map(|s| s.name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{Symbol, SymbolKind, SymbolTable};
+
+    #[test]
+    fn round_trip() {
+        let symbols = [
+            ("LOOP", 4, SymbolKind::Label),
+            ("function:Main.main", 20, SymbolKind::Function),
+            ("counter", 16, SymbolKind::Variable),
+            ("staticvar.Main.vm.0", 17, SymbolKind::Static),
+            ("SCREEN", 0x4000, SymbolKind::Predefined),
+        ];
+        let table = SymbolTable {
+            symbols: symbols
+                .iter()
+                .map(|&(name, value, kind)| Symbol {
+                    name: name.to_string(),
+                    value,
+                    kind,
+                })
+                .collect(),
+        };
+        let path = std::env::temp_dir().join(format!("symbols-{}.sym", std::process::id()));
+        table.write(&path).unwrap();
+        let read = SymbolTable::from_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        let read: Vec<_> = read
+            .unwrap()
+            .symbols
+            .into_iter()
+            .map(|s| (s.name, s.value, s.kind))
+            .collect();
+        let expected: Vec<_> = symbols
+            .iter()
+            .map(|&(name, value, kind)| (name.to_string(), value, kind))
+            .collect();
+        assert_eq!(read, expected);
+        assert_eq!(table.label(20), Some("function:Main.main"));
+        assert_eq!(table.variable(17), Some("staticvar.Main.vm.0"));
+        assert_eq!(table.label(16), None);
+    }
+
+    #[test]
+    fn invalid_lines() {
+        let path = std::env::temp_dir().join(format!("invalid-{}.sym", std::process::id()));
+        fs::write(
+            &path,
+            "// comment\nlabel 4 LOOP\nlabel LOOP\nconstant 3 X\nvariable 70000 x\n",
+        )
+        .unwrap();
+        let errors = SymbolTable::from_file(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+
+        let messages: Vec<_> = errors.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "Invalid symbol",
+                "Unknown symbol kind 'constant'",
+                "Invalid address '70000'"
+            ]
+        );
+    }
+}
@@ -0,0 +1,603 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::{
+    cpu::{Cpu, MEMORY_SIZE},
+    diagnostic::{Diagnostic, Location},
+    hex::Hex,
+};
+
+#[derive(Debug, Clone, Copy)]
+enum Variable {
+    Ram(u16),
+    A,
+    D,
+    PC,
+    Time,
+}
+
+impl TryFrom<&str> for Variable {
+    type Error = Diagnostic;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "A" => Ok(Variable::A),
+            "D" => Ok(Variable::D),
+            "PC" => Ok(Variable::PC),
+            "time" => Ok(Variable::Time),
+            _ => {
+                let address: u16 = value
+                    .strip_prefix("RAM[")
+                    .and_then(|v| v.strip_suffix(']'))
+                    .and_then(|v| v.parse().ok())
+                    .ok_or(Diagnostic::error(
+                        "test-script",
+                        format!("Unknown variable '{}'", value),
+                    ))?;
+                if address as usize >= MEMORY_SIZE {
+                    return Err(Diagnostic::error(
+                        "test-script",
+                        format!(
+                            "RAM address {} is out of range, the last one is {}",
+                            address,
+                            MEMORY_SIZE - 1
+                        ),
+                    ));
+                }
+                Ok(Variable::Ram(address))
+            }
+        }
+    }
+}
+
+impl Variable {
+    fn get(&self, cpu: &Cpu) -> u16 {
+        match self {
+            Variable::Ram(a) => cpu.ram[*a as usize],
+            Variable::A => cpu.a,
+            Variable::D => cpu.d,
+            Variable::PC => cpu.pc,
+            Variable::Time => cpu.time as u16,
+        }
+    }
+
+    fn set(&self, cpu: &mut Cpu, value: u16) -> Result<(), Diagnostic> {
+        match self {
+            Variable::Ram(a) => cpu.ram[*a as usize] = value,
+            Variable::A => cpu.a = value,
+            Variable::D => cpu.d = value,
+            Variable::PC => cpu.pc = value,
+            Variable::Time => {
+                return Err(Diagnostic::error(
+                    "test-script",
+                    "Variable 'time' is read only",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A single entry of `output-list` such as `RAM[0]%D1.6.1`.
+#[derive(Debug, Clone)]
+struct Column {
+    name: String,
+    variable: Variable,
+    format: char,
+    left: usize,
+    width: usize,
+    right: usize,
+}
+
+impl TryFrom<&str> for Column {
+    type Error = Diagnostic;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let invalid = || {
+            Diagnostic::error("test-script", format!("Invalid output format '{}'", value))
+                .with_note("formats look like %D1.6.1 with D, X or B")
+        };
+        let (name, format) = value.split_once('%').unwrap_or((value, "D1.6.1"));
+        let mut chars = format.chars();
+        let fmt = chars.next().unwrap_or('D');
+        let sizes: Vec<usize> = chars
+            .as_str()
+            .split('.')
+            .map(|s| s.parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| invalid())?;
+        if !matches!(fmt, 'D' | 'X' | 'B') || sizes.len() != 3 {
+            return Err(invalid());
+        }
+        Ok(Column {
+            name: name.to_string(),
+            variable: name.try_into()?,
+            format: fmt,
+            left: sizes[0],
+            width: sizes[1],
+            right: sizes[2],
+        })
+    }
+}
+
+impl Column {
+    fn header(&self) -> String {
+        let total = self.left + self.width + self.right;
+        let name: String = self.name.chars().take(total).collect();
+        let left = (total - name.len()) / 2;
+        format!(
+            "{:left$}{}{:right$}",
+            "",
+            name,
+            "",
+            right = total - left - name.len()
+        )
+    }
+
+    fn value(&self, cpu: &Cpu) -> String {
+        let value = self.variable.get(cpu);
+        let text = match self.format {
+            'X' => format!("{:04X}", value),
+            'B' => format!("{:016b}", value),
+            _ => format!("{}", value as i16),
+        };
+        let text = &text[text.len().saturating_sub(self.width)..];
+        format!(
+            "{:left$}{:>width$}{:right$}",
+            "",
+            text,
+            "",
+            left = self.left,
+            width = self.width,
+            right = self.right
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Command {
+    Load(String),
+    OutputFile(String),
+    CompareTo(String),
+    OutputList(Vec<Column>),
+    Set(Variable, u16, Location),
+    Repeat(u64, Vec<Command>),
+    TickTock,
+    Output,
+    Echo,
+}
+
+/// A test script for the CPU emulator as shipped with the projects.
+pub struct TestScript {
+    dir: PathBuf,
+    commands: Vec<Command>,
+}
+
+type Token = (String, Range<usize>);
+
+fn tokenize(src: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = src.char_indices().peekable();
+    let mut current = String::new();
+    let mut start = 0;
+    let push = |tokens: &mut Vec<Token>, current: &mut String, start: usize, end: usize| {
+        if !current.is_empty() {
+            tokens.push((std::mem::take(current), start..end));
+        }
+    };
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '/' if chars.peek().is_some_and(|(_, c)| *c == '/') => {
+                push(&mut tokens, &mut current, start, i);
+                for (_, c) in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek().is_some_and(|(_, c)| *c == '*') => {
+                push(&mut tokens, &mut current, start, i);
+                chars.next();
+                let mut last = ' ';
+                for (_, c) in chars.by_ref() {
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+            }
+            '"' => {
+                push(&mut tokens, &mut current, start, i);
+                let mut end = src.len();
+                for (j, c) in chars.by_ref() {
+                    if c == '"' {
+                        end = j + 1;
+                        break;
+                    }
+                    current.push(c);
+                }
+                tokens.push((std::mem::take(&mut current), i..end));
+            }
+            ',' | ';' | '{' | '}' => {
+                push(&mut tokens, &mut current, start, i);
+                tokens.push((c.to_string(), i..i + 1));
+            }
+            c if c.is_whitespace() => push(&mut tokens, &mut current, start, i),
+            c => {
+                if current.is_empty() {
+                    start = i;
+                }
+                current.push(c);
+            }
+        }
+    }
+    push(&mut tokens, &mut current, start, src.len());
+    tokens
+}
+
+fn parse_value(value: &str) -> Option<u16> {
+    if let Some(v) = value.strip_prefix("%X") {
+        u16::from_str_radix(v, 16).ok()
+    } else if let Some(v) = value.strip_prefix("%B") {
+        u16::from_str_radix(v, 2).ok()
+    } else {
+        let v = value.strip_prefix("%D").unwrap_or(value);
+        v.parse::<i16>()
+            .map(|v| v as u16)
+            .or_else(|_| v.parse::<u16>())
+            .ok()
+    }
+}
+
+struct Parser {
+    file: Rc<str>,
+    tokens: std::iter::Peekable<std::vec::IntoIter<Token>>,
+    end: usize,
+}
+
+impl Parser {
+    fn location(&self, span: &Range<usize>) -> Location {
+        Location::new(&self.file, span.clone())
+    }
+
+    fn error(&self, span: &Range<usize>, message: String) -> Diagnostic {
+        Diagnostic::error("test-script", message).with_location(self.location(span))
+    }
+
+    fn next(&mut self, what: &str) -> Result<Token, Diagnostic> {
+        self.tokens.next().ok_or_else(|| {
+            self.error(
+                &(self.end..self.end),
+                format!("Unexpected end of script, expected {}", what),
+            )
+        })
+    }
+
+    fn commands(&mut self, nested: bool) -> Result<Vec<Command>, Diagnostic> {
+        let mut commands = Vec::new();
+        while let Some((token, span)) = self.tokens.next() {
+            let command = match token.as_str() {
+                "," | ";" => continue,
+                "}" if nested => return Ok(commands),
+                "load" => Command::Load(self.next("file name")?.0),
+                "output-file" => Command::OutputFile(self.next("file name")?.0),
+                "compare-to" => Command::CompareTo(self.next("file name")?.0),
+                "output-list" => {
+                    let mut columns = Vec::new();
+                    while self
+                        .tokens
+                        .peek()
+                        .is_some_and(|(t, _)| t != "," && t != ";")
+                    {
+                        let (column, span) = self.next("column")?;
+                        columns.push(
+                            Column::try_from(column.as_str())
+                                .map_err(|e| e.with_location(self.location(&span)))?,
+                        );
+                    }
+                    Command::OutputList(columns)
+                }
+                "set" => {
+                    let (variable, vspan) = self.next("variable")?;
+                    let variable = Variable::try_from(variable.as_str())
+                        .map_err(|e| e.with_location(self.location(&vspan)))?;
+                    let (value, span) = self.next("value")?;
+                    let value = parse_value(&value)
+                        .ok_or_else(|| self.error(&span, format!("Invalid value '{}'", value)))?;
+                    Command::Set(variable, value, self.location(&vspan))
+                }
+                "repeat" => {
+                    let (count, span) = self.next("repeat count")?;
+                    if count == "{" {
+                        // The official emulator repeats forever, which never ends without its UI
+                        return Err(self
+                            .error(&span, "Repeat without a count is not supported".into())
+                            .with_note("give the number of iterations, as in `repeat 100 {`"));
+                    }
+                    let count = count.parse().map_err(|_| {
+                        self.error(&span, format!("Invalid repeat count '{}'", count))
+                    })?;
+                    let (brace, span) = self.next("'{'")?;
+                    if brace != "{" {
+                        return Err(self.error(&span, "Expected '{' after repeat count".into()));
+                    }
+                    Command::Repeat(count, self.commands(true)?)
+                }
+                "ticktock" => Command::TickTock,
+                "output" => Command::Output,
+                "echo" => {
+                    self.next("message")?;
+                    Command::Echo
+                }
+                other => {
+                    return Err(self.error(
+                        &span,
+                        format!("Unsupported test script command '{}'", other),
+                    ));
+                }
+            };
+            commands.push(command);
+        }
+        if nested {
+            return Err(self.error(
+                &(self.end..self.end),
+                "Missing '}' at end of repeat block".to_string(),
+            ));
+        }
+        Ok(commands)
+    }
+}
+
+struct State<'a> {
+    dir: &'a Path,
+    loader: &'a dyn Fn(&Path) -> Result<Hex, Vec<Diagnostic>>,
+    cpu: Option<Cpu>,
+    columns: Vec<Column>,
+    output_file: Option<PathBuf>,
+    output: Vec<String>,
+    /// The lines of the compare-to file with their location
+    compare: Option<Vec<(String, Location)>>,
+}
+
+impl State<'_> {
+    fn cpu(&mut self) -> Result<&mut Cpu, Diagnostic> {
+        self.cpu.as_mut().ok_or(Diagnostic::error(
+            "test-script",
+            "No program loaded before running the CPU",
+        ))
+    }
+
+    fn emit(&mut self, line: String) -> Result<(), Diagnostic> {
+        let lineno = self.output.len();
+        self.output.push(line.clone());
+        if let Some(compare) = &self.compare {
+            let (expected, location) = match compare.get(lineno) {
+                Some((l, location)) => (l.trim(), Some(location)),
+                None => ("", None),
+            };
+            if expected != line.trim() {
+                return Err(Diagnostic::error(
+                    "comparison-failure",
+                    format!("Comparison failure at line {}", lineno + 1),
+                )
+                .or_location(location)
+                .with_label("expected this line")
+                .with_note(format!("actual output: {}", line)));
+            }
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, commands: &[Command]) -> Result<(), Vec<Diagnostic>> {
+        for command in commands {
+            match command {
+                Command::Load(file) => {
+                    let hex = (self.loader)(&self.dir.join(file))?;
+                    self.cpu = Some(Cpu::new(&hex));
+                }
+                Command::OutputFile(file) => self.output_file = Some(self.dir.join(file)),
+                Command::CompareTo(file) => {
+                    let path = self.dir.join(file);
+                    let mut src = String::new();
+                    File::open(&path)
+                        .map_err(|e| Diagnostic::io(&path, e))?
+                        .read_to_string(&mut src)
+                        .map_err(|e| Diagnostic::io(&path, e))?;
+                    let name: Rc<str> = path.to_string_lossy().into();
+                    let mut offset = 0;
+                    let mut lines = Vec::new();
+                    for line in src.split_inclusive('\n') {
+                        let content = line.trim_end_matches(['\r', '\n']);
+                        lines.push((
+                            content.to_string(),
+                            Location::new(&name, offset..offset + content.len()),
+                        ));
+                        offset += line.len();
+                    }
+                    self.compare = Some(lines);
+                }
+                Command::OutputList(columns) => {
+                    self.columns = columns.clone();
+                    let header = columns.iter().map(|c| c.header()).collect::<Vec<_>>();
+                    self.emit(format!("|{}|", header.join("|")))?;
+                }
+                Command::Set(variable, value, location) => variable
+                    .set(self.cpu()?, *value)
+                    .map_err(|e| e.with_location(location.clone()))?,
+                Command::Repeat(count, commands) => {
+                    for _ in 0..*count {
+                        self.execute(commands)?;
+                    }
+                }
+                Command::TickTock => self.cpu()?.step(),
+                Command::Output => {
+                    let cpu = self.cpu.as_ref().ok_or(Diagnostic::error(
+                        "test-script",
+                        "No program loaded before output",
+                    ))?;
+                    let values = self
+                        .columns
+                        .iter()
+                        .map(|c| c.value(cpu))
+                        .collect::<Vec<_>>();
+                    self.emit(format!("|{}|", values.join("|")))?;
+                }
+                Command::Echo => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TestScript {
+    pub fn from_file(path: &Path) -> Result<Self, Vec<Diagnostic>> {
+        let mut src = String::new();
+        File::open(path)
+            .map_err(|e| Diagnostic::io(path, e))?
+            .read_to_string(&mut src)
+            .map_err(|e| Diagnostic::io(path, e))?;
+        let mut parser = Parser {
+            file: path.to_string_lossy().into(),
+            tokens: tokenize(&src).into_iter().peekable(),
+            end: src.len(),
+        };
+        Ok(TestScript {
+            dir: path.parent().unwrap_or(Path::new(".")).to_path_buf(),
+            commands: parser.commands(false)?,
+        })
+    }
+
+    /// Runs the script and compares its output against the compare-to file if one is given.
+    /// `loader` builds the program named by the load command.
+    pub fn run(
+        &self,
+        loader: &dyn Fn(&Path) -> Result<Hex, Vec<Diagnostic>>,
+    ) -> Result<(), Vec<Diagnostic>> {
+        let mut state = State {
+            dir: &self.dir,
+            loader,
+            cpu: None,
+            columns: Vec::new(),
+            output_file: None,
+            output: Vec::new(),
+            compare: None,
+        };
+        let result = state.execute(&self.commands);
+        if let Some(path) = &state.output_file {
+            let mut file = File::create(path).map_err(|e| Diagnostic::io(path, e))?;
+            for line in &state.output {
+                writeln!(file, "{}", line).map_err(|e| Diagnostic::io(path, e))?;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn cpu() -> Cpu {
+        Cpu::new(&Hex {
+            instructions: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn tokens() {
+        let src =
+            "load Max.hack, // comment\n/* block\n */ echo \"two words\";\nrepeat 2 {ticktock;}";
+        let tokens: Vec<_> = tokenize(src).into_iter().map(|(t, _)| t).collect();
+        assert_eq!(
+            tokens,
+            [
+                "load",
+                "Max.hack",
+                ",",
+                "echo",
+                "two words",
+                ";",
+                "repeat",
+                "2",
+                "{",
+                "ticktock",
+                ";",
+                "}"
+            ]
+        );
+        let (_, span) = &tokenize(src)[4];
+        assert_eq!(&src[span.clone()], "\"two words\"");
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(parse_value("42"), Some(42));
+        assert_eq!(parse_value("-1"), Some(0xFFFF));
+        assert_eq!(parse_value("%D-2"), Some(0xFFFE));
+        assert_eq!(parse_value("65535"), Some(0xFFFF));
+        assert_eq!(parse_value("%X7FFF"), Some(0x7FFF));
+        assert_eq!(parse_value("%B101"), Some(5));
+        assert_eq!(parse_value("%B2"), None);
+        assert_eq!(parse_value("D"), None);
+    }
+
+    #[test]
+    fn columns() {
+        let mut cpu = cpu();
+        cpu.ram[0] = 0xFFFF;
+        let column = |format: &str| Column::try_from(format).unwrap();
+        assert_eq!(column("RAM[0]%D1.6.1").header(), " RAM[0] ");
+        assert_eq!(column("RAM[0]%D1.6.1").value(&cpu), "     -1 ");
+        assert_eq!(column("RAM[0]%X1.4.1").value(&cpu), " FFFF ");
+        assert_eq!(column("RAM[0]%B0.4.0").value(&cpu), "1111");
+        assert_eq!(column("D").header(), "   D    ");
+        assert!(Column::try_from("D%Q1.6.1").is_err());
+        assert!(Column::try_from("D%D1.6").is_err());
+        assert!(Column::try_from("RAM[32768]").is_err());
+    }
+
+    #[test]
+    fn repeat_without_count() {
+        let mut parser = Parser {
+            file: "test.tst".into(),
+            tokens: tokenize("repeat { ticktock; }").into_iter().peekable(),
+            end: 20,
+        };
+        let error = parser.commands(false).unwrap_err();
+        assert_eq!(error.location.unwrap().span, 7..8);
+    }
+
+    #[test]
+    fn comparison_failure() {
+        let dir = std::env::temp_dir().join(format!("tst-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("Prog.tst"),
+            "load Prog.hack, output-file Prog.out, compare-to Prog.cmp,\n\
+             output-list D%D1.6.1;\nrepeat 2 { ticktock; } output;\n",
+        )
+        .unwrap();
+        fs::write(dir.join("Prog.cmp"), "|   D    |\n|      6 |\n").unwrap();
+        let script = TestScript::from_file(&dir.join("Prog.tst")).unwrap();
+        // @5, D=A
+        let loader = |_: &Path| {
+            Ok(Hex {
+                instructions: vec![0x0005, 0xEC10],
+            })
+        };
+        let errors = script.run(&loader).unwrap_err();
+        let output = fs::read_to_string(dir.join("Prog.out")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(errors[0].code, "comparison-failure");
+        assert_eq!(errors[0].message, "Comparison failure at line 2");
+        assert_eq!(errors[0].location.as_ref().unwrap().span, 11..21);
+        assert_eq!(output, "|   D    |\n|      5 |\n");
+    }
+}
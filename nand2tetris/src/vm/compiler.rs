@@ -1,19 +1,25 @@
-use std::path::PathBuf;
+use std::{mem::replace, rc::Rc};
 
-use crate::assembly::{Compute, Instruction, Jump, LoadData, Target};
+use crate::{
+    assembly::{Assembly, Compute, Instruction, Jump, LoadData, Target},
+    diagnostic::Location,
+    symbols::{FUNCTION_PREFIX, STATIC_PREFIX},
+};
 
-use super::{Function, PopDest, PushSource, Statement};
+use super::{Function, Lowering, PopDest, PushSource, Routine, Spanned, Statement};
 
 #[derive(Debug)]
 pub struct LabelGenerator {
     filename: String,
+    function: Option<String>,
     last_statement: u16,
 }
 
 impl LabelGenerator {
-    pub fn new(filename: &PathBuf) -> Self {
+    pub fn new(filename: &str) -> Self {
         LabelGenerator {
-            filename: filename.to_str().unwrap().to_string(),
+            filename: filename.to_string(),
+            function: None,
             last_statement: 0,
         }
     }
@@ -21,69 +27,103 @@ impl LabelGenerator {
     fn next_statement(&mut self) -> String {
         let val = self.last_statement;
         self.last_statement += 1;
-        format!("{}-stmt-{}", &self.filename, val)
+        format!("{}$stmt.{}", &self.filename, val)
     }
+
+    fn set_function(&mut self, function: &str) {
+        self.function = Some(function.to_string());
+    }
+
+    /// Qualifies a VM label with the enclosing function as `function$label`, or with the file
+    /// as `file$label` outside of functions.
+    fn scoped_label(&self, label: &str) -> String {
+        match &self.function {
+            Some(f) => format!("{}${}", f, label),
+            None => format!("{}${}", self.filename, label),
+        }
+    }
+
+    fn next_return(&mut self) -> String {
+        let val = self.last_statement;
+        self.last_statement += 1;
+        format!("{}$ret.{}", &self.filename, val)
+    }
+}
+
+/// Initializes the stack pointer and calls Sys.init.
+pub fn bootstrap() -> Vec<Instruction> {
+    let mut lg = LabelGenerator::new("bootstrap");
+    let mut out = Statement::set_d(256);
+    out.extend([
+        Instruction::Load {
+            data: LoadData::label("SP"),
+        },
+        Instruction::Command {
+            compute: Compute::D,
+            target: Target::M,
+            jump: Jump::NONE,
+        },
+    ]);
+    out.append(&mut Statement::Call("Sys.init".to_string(), 0).compile(&mut lg, &[]));
+    out
+}
+
+/// Label marking the end of the shared routines.
+const RUNTIME_END: &str = "$runtime.end";
+
+/// The shared routines of the given statements, each emitted once. Bootstrap code calls
+/// Sys.init before reaching them, but a program without it starts right at them, so unless
+/// `bootstrap_jumps_over` they are skipped with a jump.
+pub fn runtime(statements: &[&Statement], bootstrap_jumps_over: bool) -> Vec<Instruction> {
+    let mut labels = Vec::new();
+    let mut routines = Vec::new();
+    for statement in statements {
+        let Some((_, label)) = statement.routine() else {
+            continue;
+        };
+        if labels.contains(&label) {
+            continue;
+        }
+        labels.push(label);
+        routines.push(Instruction::label(label));
+        routines.append(&mut match statement {
+            Statement::Eq => Statement::compare_routine(label, Jump::JEQ),
+            Statement::Lt => Statement::compare_routine(label, Jump::JLT),
+            Statement::Gt => Statement::compare_routine(label, Jump::JGT),
+            Statement::Call(_, _) => Statement::call_routine(),
+            _ => Statement::return_from_function(),
+        });
+    }
+    if routines.is_empty() || bootstrap_jumps_over {
+        return routines;
+    }
+    let mut out = [
+        Instruction::Load {
+            data: LoadData::label(RUNTIME_END),
+        },
+        Instruction::Command {
+            compute: Compute::Zero,
+            target: Target::empty(),
+            jump: Jump::JMP,
+        },
+    ]
+    .to_vec();
+    out.append(&mut routines);
+    out.push(Instruction::label(RUNTIME_END));
+    out
 }
 
 impl Statement {
     fn set_d(val: u16) -> Vec<Instruction> {
-        [
-            Instruction::Load {
-                data: LoadData::Data(val),
-            },
-            Instruction::Command {
-                compute: Compute::A,
-                target: Target::D,
-                jump: Jump::NONE,
-            },
-        ]
-        .to_vec()
+        Instruction::load_d(val)
     }
 
     fn push_d() -> Vec<Instruction> {
-        [
-            Instruction::Load {
-                data: LoadData::label("SP"),
-            },
-            Instruction::Command {
-                compute: Compute::M,
-                target: Target::A,
-                jump: Jump::NONE,
-            },
-            Instruction::Command {
-                compute: Compute::D,
-                target: Target::M,
-                jump: Jump::NONE,
-            },
-            Instruction::Load {
-                data: LoadData::label("SP"),
-            },
-            Instruction::Command {
-                compute: Compute::MplusOne,
-                target: Target::M,
-                jump: Jump::NONE,
-            },
-        ]
-        .to_vec()
+        Instruction::push_d()
     }
 
     fn pop(target: Target) -> Vec<Instruction> {
-        [
-            Instruction::Load {
-                data: LoadData::label("SP"),
-            },
-            Instruction::Command {
-                compute: Compute::MminOne,
-                target: Target::A | Target::M,
-                jump: Jump::NONE,
-            },
-            Instruction::Command {
-                compute: Compute::M,
-                target,
-                jump: Jump::NONE,
-            },
-        ]
-        .to_vec()
+        Instruction::pop(target)
     }
 
     fn cmp(lg: &mut LabelGenerator, jmp: Jump) -> Vec<Instruction> {
@@ -174,8 +214,9 @@ impl Statement {
         .to_vec()
     }
 
-    fn push_common(label: &str, index: u16) -> Vec<Instruction> {
-        let mut out = [
+    /// Reads `label[index]` into D.
+    fn read_common(label: &str, index: u16) -> Vec<Instruction> {
+        [
             Instruction::Load {
                 data: LoadData::label(label),
             },
@@ -198,12 +239,12 @@ impl Statement {
                 jump: Jump::NONE,
             },
         ]
-        .to_vec();
-        out.append(&mut Self::push_d());
-        out
+        .to_vec()
     }
 
-    fn pop_common(label: &str, index: u16) -> Vec<Instruction> {
+    /// Writes the value the given instructions put in D to `label[index]`. The address is
+    /// computed first and kept in R13, as the value may need D to be computed.
+    fn write_common(label: &str, index: u16, mut value: Vec<Instruction>) -> Vec<Instruction> {
         let mut out = [
             Instruction::Load {
                 data: LoadData::label(label),
@@ -231,7 +272,7 @@ impl Statement {
             },
         ]
         .to_vec();
-        out.append(&mut Self::pop(Target::D));
+        out.append(&mut value);
         out.extend(
             [
                 Instruction::Load {
@@ -253,22 +294,26 @@ impl Statement {
         out
     }
 
+    fn pop_common(label: &str, index: u16) -> Vec<Instruction> {
+        Self::write_common(label, index, Self::pop(Target::D))
+    }
+
     fn static_name(filename: &str, index: u16) -> String {
-        format!("staticvar.{}.{}", filename, index)
+        format!("{}{}.{}", STATIC_PREFIX, filename, index)
     }
 
     fn temp_name(index: u16) -> String {
-        assert!(index <= 7);
+        debug_assert!(index <= 7, "temp indices are checked by Ast::validate");
         format!("R{}", index + 5)
     }
 
     fn pointer_name(index: u16) -> String {
-        assert!(index <= 1);
+        debug_assert!(index <= 1, "pointer indices are checked by Ast::validate");
         format!("R{}", index + 3)
     }
 
-    fn push_fixed(label: &str) -> Vec<Instruction> {
-        let mut out = [
+    fn read_fixed(label: &str) -> Vec<Instruction> {
+        [
             Instruction::Load {
                 data: LoadData::label(label),
             },
@@ -278,14 +323,11 @@ impl Statement {
                 jump: Jump::NONE,
             },
         ]
-        .to_vec();
-        out.append(&mut Self::push_d());
-        out
+        .to_vec()
     }
 
-    fn pop_fixed(label: &str) -> Vec<Instruction> {
-        let mut out = Self::pop(Target::D);
-        out.extend(
+    fn write_fixed(label: &str, mut value: Vec<Instruction>) -> Vec<Instruction> {
+        value.extend(
             [
                 Instruction::Load {
                     data: LoadData::label(label),
@@ -298,235 +340,859 @@ impl Statement {
             ]
             .to_vec(),
         );
+        value
+    }
+
+    fn push_fixed(label: &str) -> Vec<Instruction> {
+        let mut out = Self::read_fixed(label);
+        out.append(&mut Self::push_d());
         out
     }
 
-    pub fn compile(&self, lg: &mut LabelGenerator) -> Vec<Instruction> {
-        match self {
-            Statement::Not => Self::compute1(Compute::NotM),
-            Statement::And => Self::compute2(Compute::DandM),
-            Statement::Or => Self::compute2(Compute::DorM),
-            Statement::Neg => Self::compute1(Compute::NegM),
-            Statement::Add => Self::compute2(Compute::DplusM),
-            Statement::Sub => Self::compute2(Compute::MminD),
-            Statement::Eq => Self::cmp(lg, Jump::JEQ),
-            Statement::Lt => Self::cmp(lg, Jump::JLT),
-            Statement::Gt => Self::cmp(lg, Jump::JGT),
+    /// Reads a value of a segment into D.
+    fn read(source: &PushSource, index: u16) -> Vec<Instruction> {
+        match source {
+            PushSource::Constant => Self::set_d(index),
+            PushSource::Local => Self::read_common("LCL", index),
+            PushSource::Argument => Self::read_common("ARG", index),
+            PushSource::This => Self::read_common("THIS", index),
+            PushSource::That => Self::read_common("THAT", index),
+            PushSource::Static(filename) => Self::read_fixed(&Self::static_name(filename, index)),
+            PushSource::Temp => Self::read_fixed(&Self::temp_name(index)),
+            PushSource::Pointer => Self::read_fixed(&Self::pointer_name(index)),
+        }
+    }
 
-            Statement::Push(PushSource::Constant, i) => {
-                let mut out = Statement::set_d(*i);
-                out.append(&mut Self::push_d());
-                out
-            }
-            Statement::Push(PushSource::Local, i) => Self::push_common("LCL", *i),
-            Statement::Push(PushSource::Argument, i) => Self::push_common("ARG", *i),
-            Statement::Push(PushSource::This, i) => Self::push_common("THIS", *i),
-            Statement::Push(PushSource::That, i) => Self::push_common("THAT", *i),
-            Statement::Push(PushSource::Static(filename), i) => {
-                Self::push_fixed(&Self::static_name(filename, *i))
-            }
-            Statement::Push(PushSource::Temp, i) => Self::push_fixed(&Self::temp_name(*i)),
-            Statement::Push(PushSource::Pointer, i) => Self::push_fixed(&Self::pointer_name(*i)),
-
-            Statement::Pop(PopDest::Local, i) => Self::pop_common("LCL", *i),
-            Statement::Pop(PopDest::Argument, i) => Self::pop_common("ARG", *i),
-            Statement::Pop(PopDest::This, i) => Self::pop_common("THIS", *i),
-            Statement::Pop(PopDest::That, i) => Self::pop_common("THAT", *i),
-            Statement::Pop(PopDest::Static(filename), i) => {
-                Self::pop_fixed(&Self::static_name(filename, *i))
-            }
-            Statement::Pop(PopDest::Temp, i) => Self::pop_fixed(&Self::temp_name(*i)),
-            Statement::Pop(PopDest::Pointer, i) => Self::pop_fixed(&Self::pointer_name(*i)),
-            Statement::Label(l) => [Instruction::Label { label: l.clone() }].to_vec(),
-            Statement::Goto(l) => [
-                Instruction::Load {
-                    data: LoadData::label(l),
-                },
-                Instruction::Command {
-                    compute: Compute::Zero,
-                    target: Target::empty(),
-                    jump: Jump::JMP,
-                },
-            ]
-            .to_vec(),
-            Statement::IfGoto(l) => {
-                let mut out = Self::pop(Target::D);
-                out.extend([
-                    Instruction::Load {
-                        data: LoadData::label(l),
-                    },
-                    Instruction::Command {
-                        compute: Compute::D,
-                        target: Target::empty(),
-                        jump: Jump::JNE,
-                    },
-                ]);
-                out
+    /// Writes the value the given instructions put in D into a segment.
+    fn write(dest: &PopDest, index: u16, value: Vec<Instruction>) -> Vec<Instruction> {
+        match dest {
+            PopDest::Local => Self::write_common("LCL", index, value),
+            PopDest::Argument => Self::write_common("ARG", index, value),
+            PopDest::This => Self::write_common("THIS", index, value),
+            PopDest::That => Self::write_common("THAT", index, value),
+            PopDest::Static(filename) => {
+                Self::write_fixed(&Self::static_name(filename, index), value)
             }
-            Statement::Return => {
-                // Move the return value on the stack to ARG[0] which will be top of stack later
-                let mut out = Self::pop_common("ARG", 0);
+            PopDest::Temp => Self::write_fixed(&Self::temp_name(index), value),
+            PopDest::Pointer => Self::write_fixed(&Self::pointer_name(index), value),
+            PopDest::Constant => unreachable!("popping into constant is rejected by Ast::validate"),
+        }
+    }
+
+    /// The shared routine the statement can be translated into a call of, with its label.
+    pub(super) fn routine(&self) -> Option<(Routine, &'static str)> {
+        match self {
+            Statement::Eq => Some((Routine::Compare, "$runtime.eq")),
+            Statement::Lt => Some((Routine::Compare, "$runtime.lt")),
+            Statement::Gt => Some((Routine::Compare, "$runtime.gt")),
+            Statement::Call(_, _) => Some((Routine::Call, "$runtime.call")),
+            Statement::Return => Some((Routine::Return, "$runtime.return")),
+            _ => None,
+        }
+    }
+
+    /// Jumps to a shared routine. Except for return, the routine jumps back to the address
+    /// passed in D.
+    fn use_routine(&self, lg: &mut LabelGenerator, label: &str) -> Vec<Instruction> {
+        let jump = [
+            Instruction::Load {
+                data: LoadData::label(label),
+            },
+            Instruction::Command {
+                compute: Compute::Zero,
+                target: Target::empty(),
+                jump: Jump::JMP,
+            },
+        ];
+        let mut out = match self {
+            Statement::Return => return jump.to_vec(),
+            // The call routine takes the number of arguments in R13 and the function in R14
+            Statement::Call(function, args) => {
+                let mut out = Self::set_d(*args);
                 out.extend([
-                    // Reset the top of the stack to be at current ARG[1]
-                    Instruction::Load {
-                        data: LoadData::label("ARG"),
-                    },
-                    Instruction::Command {
-                        compute: Compute::MplusOne,
-                        target: Target::D,
-                        jump: Jump::NONE,
-                    },
-                    Instruction::Load {
-                        data: LoadData::label("SP"),
-                    },
-                    Instruction::Command {
-                        compute: Compute::D,
-                        target: Target::M,
-                        jump: Jump::NONE,
-                    },
-                    // Restore Segment Pointers R14 is scratch memory of current recovery pointer
-                    Instruction::Load {
-                        data: LoadData::label("LCL"),
-                    },
-                    Instruction::Command {
-                        compute: Compute::MminOne,
-                        target: Target::D,
-                        jump: Jump::NONE,
-                    },
-                    // D now contains the "saved THAT" address. we save it first in R14 before
-                    // continuing
-                    Instruction::Load {
-                        data: LoadData::label("R14"),
-                    },
-                    Instruction::Command {
-                        compute: Compute::D,
-                        target: Target::A | Target::M,
-                        jump: Jump::NONE,
-                    },
-                    // Now restore THAT
-                    Instruction::Command {
-                        compute: Compute::M,
-                        target: Target::D,
-                        jump: Jump::NONE,
-                    },
-                    Instruction::Load {
-                        data: LoadData::label("THAT"),
-                    },
-                    Instruction::Command {
-                        compute: Compute::D,
-                        target: Target::M,
-                        jump: Jump::NONE,
-                    },
-                    // Now the same for "saved THIS" which is -1 again.
-                    Instruction::Load {
-                        data: LoadData::label("R14"),
-                    },
-                    Instruction::Command {
-                        compute: Compute::MminOne,
-                        target: Target::A | Target::M,
-                        jump: Jump::NONE,
-                    },
-                    Instruction::Command {
-                        compute: Compute::M,
-                        target: Target::D,
-                        jump: Jump::NONE,
-                    },
                     Instruction::Load {
-                        data: LoadData::label("THIS"),
+                        data: LoadData::label("R13"),
                     },
                     Instruction::Command {
                         compute: Compute::D,
                         target: Target::M,
                         jump: Jump::NONE,
                     },
-                    // Now the same for "saved ARG" which is -1 again.
                     Instruction::Load {
-                        data: LoadData::label("R14"),
-                    },
-                    Instruction::Command {
-                        compute: Compute::MminOne,
-                        target: Target::A | Target::M,
-                        jump: Jump::NONE,
+                        data: LoadData::Label(Function::function_label(function)),
                     },
                     Instruction::Command {
-                        compute: Compute::M,
+                        compute: Compute::A,
                         target: Target::D,
                         jump: Jump::NONE,
                     },
-                    Instruction::Load {
-                        data: LoadData::label("ARG"),
-                    },
-                    Instruction::Command {
-                        compute: Compute::D,
-                        target: Target::M,
-                        jump: Jump::NONE,
-                    },
-                    // Now the same for "saved LCL" which is -1 again.
                     Instruction::Load {
                         data: LoadData::label("R14"),
                     },
-                    Instruction::Command {
-                        compute: Compute::MminOne,
-                        target: Target::A | Target::M,
-                        jump: Jump::NONE,
-                    },
-                    Instruction::Command {
-                        compute: Compute::M,
-                        target: Target::D,
-                        jump: Jump::NONE,
-                    },
-                    Instruction::Load {
-                        data: LoadData::label("LCL"),
-                    },
                     Instruction::Command {
                         compute: Compute::D,
                         target: Target::M,
                         jump: Jump::NONE,
                     },
-                    // Now the next -1 is the return address that we should jump to
-                    Instruction::Load {
-                        data: LoadData::label("R14"),
-                    },
-                    Instruction::Command {
-                        compute: Compute::MminOne,
-                        target: Target::A,
-                        jump: Jump::NONE,
-                    },
-                    // A now contains the address of the return address
-                    Instruction::Command {
-                        compute: Compute::M,
-                        target: Target::A,
-                        jump: Jump::JMP,
-                    },
                 ]);
                 out
             }
-        }
-    }
-}
-
-impl Function {
-    fn function_label(name: &str) -> String {
-        format!("function:{}", name)
-    }
-
-    pub fn compile(&self, lg: &mut LabelGenerator) -> Vec<Instruction> {
-        let mut out = [
-            Instruction::Label {
-                label: Self::function_label(&self.name),
-            },
+            _ => Vec::new(),
+        };
+        let retlabel = lg.next_return();
+        out.extend([
             Instruction::Load {
-                data: LoadData::label("LCL"),
+                data: LoadData::label(&retlabel),
             },
             Instruction::Command {
-                compute: Compute::M,
-                target: Target::A,
+                compute: Compute::A,
+                target: Target::D,
                 jump: Jump::NONE,
             },
-        ]
-        .to_vec();
-        for _ in 0..self.locals {
+        ]);
+        out.extend(jump);
+        out.push(Instruction::Label { label: retlabel });
+        out
+    }
+
+    /// Compares the two values on top of the stack like `cmp`, returning to the address in D.
+    fn compare_routine(label: &str, jmp: Jump) -> Vec<Instruction> {
+        let truelabel = format!("{}.true", label);
+        [
+            Instruction::Load {
+                data: LoadData::label("R13"),
+            },
+            Instruction::Command {
+                compute: Compute::D,
+                target: Target::M,
+                jump: Jump::NONE,
+            },
+            Instruction::Load {
+                data: LoadData::label("SP"),
+            },
+            Instruction::Command {
+                compute: Compute::MminOne,
+                target: Target::A | Target::M,
+                jump: Jump::NONE,
+            },
+            Instruction::Command {
+                compute: Compute::M,
+                target: Target::D,
+                jump: Jump::NONE,
+            },
+            Instruction::Command {
+                compute: Compute::AminOne,
+                target: Target::A,
+                jump: Jump::NONE,
+            },
+            Instruction::Command {
+                compute: Compute::MminD,
+                target: Target::D,
+                jump: Jump::NONE,
+            },
+            // Assume the comparison holds and correct it otherwise
+            Instruction::Command {
+                compute: Compute::NegOne,
+                target: Target::M,
+                jump: Jump::NONE,
+            },
+            Instruction::Load {
+                data: LoadData::label(&truelabel),
+            },
+            Instruction::Command {
+                compute: Compute::D,
+                target: Target::empty(),
+                jump: jmp,
+            },
+            Instruction::Load {
+                data: LoadData::label("SP"),
+            },
+            Instruction::Command {
+                compute: Compute::MminOne,
+                target: Target::A,
+                jump: Jump::NONE,
+            },
+            Instruction::Command {
+                compute: Compute::Zero,
+                target: Target::M,
+                jump: Jump::NONE,
+            },
+            Instruction::label(&truelabel),
+            Instruction::Load {
+                data: LoadData::label("R13"),
+            },
+            Instruction::Command {
+                compute: Compute::M,
+                target: Target::A,
+                jump: Jump::NONE,
+            },
+            Instruction::Command {
+                compute: Compute::Zero,
+                target: Target::empty(),
+                jump: Jump::JMP,
+            },
+        ]
+        .to_vec()
+    }
+
+    /// Pushes the return address in D followed by the segment pointers of the caller.
+    fn push_frame() -> Vec<Instruction> {
+        let mut out = Self::push_d();
+        out.append(&mut Self::push_fixed("LCL"));
+        out.append(&mut Self::push_fixed("ARG"));
+        out.append(&mut Self::push_fixed("THIS"));
+        out.append(&mut Self::push_fixed("THAT"));
+        out
+    }
+
+    /// Calls the function in R14 with the number of arguments in R13, returning to the
+    /// address in D.
+    fn call_routine() -> Vec<Instruction> {
+        let mut out = Self::push_frame();
+        out.extend([
+            // ARG = SP - 5 - args
+            Instruction::Load {
+                data: LoadData::label("SP"),
+            },
+            Instruction::Command {
+                compute: Compute::M,
+                target: Target::D,
+                jump: Jump::NONE,
+            },
+            Instruction::Load {
+                data: LoadData::label("R13"),
+            },
+            Instruction::Command {
+                compute: Compute::DminM,
+                target: Target::D,
+                jump: Jump::NONE,
+            },
+            Instruction::Load {
+                data: LoadData::Data(5),
+            },
+            Instruction::Command {
+                compute: Compute::DminA,
+                target: Target::D,
+                jump: Jump::NONE,
+            },
+            Instruction::Load {
+                data: LoadData::label("ARG"),
+            },
+            Instruction::Command {
+                compute: Compute::D,
+                target: Target::M,
+                jump: Jump::NONE,
+            },
+            // LCL = SP
+            Instruction::Load {
+                data: LoadData::label("SP"),
+            },
+            Instruction::Command {
+                compute: Compute::M,
+                target: Target::D,
+                jump: Jump::NONE,
+            },
+            Instruction::Load {
+                data: LoadData::label("LCL"),
+            },
+            Instruction::Command {
+                compute: Compute::D,
+                target: Target::M,
+                jump: Jump::NONE,
+            },
+            Instruction::Load {
+                data: LoadData::label("R14"),
+            },
+            Instruction::Command {
+                compute: Compute::M,
+                target: Target::A,
+                jump: Jump::NONE,
+            },
+            Instruction::Command {
+                compute: Compute::Zero,
+                target: Target::empty(),
+                jump: Jump::JMP,
+            },
+        ]);
+        out
+    }
+
+    /// Pushes the cached top of the stack, so that the whole stack is in RAM again.
+    fn spill(cached: &mut bool) -> Vec<Instruction> {
+        match replace(cached, false) {
+            true => Self::push_d(),
+            false => Vec::new(),
+        }
+    }
+
+    /// Takes the top of the stack into D, from the cache or from RAM.
+    fn take(cached: &mut bool) -> Vec<Instruction> {
+        match replace(cached, false) {
+            true => Vec::new(),
+            false => Self::pop(Target::D),
+        }
+    }
+
+    /// Writes D to `label[index]`. Small indices are reached by incrementing A, which keeps
+    /// D, larger ones need D to compute the address so the value is kept in R14 meanwhile.
+    fn write_d_common(label: &str, index: u16) -> Vec<Instruction> {
+        let command = |compute, target| Instruction::Command {
+            compute,
+            target,
+            jump: Jump::NONE,
+        };
+        if index < 10 {
+            let mut out = vec![
+                Instruction::Load {
+                    data: LoadData::label(label),
+                },
+                command(Compute::M, Target::A),
+            ];
+            out.extend((0..index).map(|_| command(Compute::AplusOne, Target::A)));
+            out.push(command(Compute::D, Target::M));
+            return out;
+        }
+        let mut out = vec![
+            Instruction::Load {
+                data: LoadData::label("R14"),
+            },
+            command(Compute::D, Target::M),
+        ];
+        out.append(&mut Self::write_common(
+            label,
+            index,
+            vec![
+                Instruction::Load {
+                    data: LoadData::label("R14"),
+                },
+                command(Compute::M, Target::D),
+            ],
+        ));
+        out
+    }
+
+    /// Writes D into a segment.
+    fn write_d(dest: &PopDest, index: u16) -> Vec<Instruction> {
+        match dest {
+            PopDest::Local => Self::write_d_common("LCL", index),
+            PopDest::Argument => Self::write_d_common("ARG", index),
+            PopDest::This => Self::write_d_common("THIS", index),
+            PopDest::That => Self::write_d_common("THAT", index),
+            PopDest::Static(filename) => {
+                Self::write_fixed(&Self::static_name(filename, index), Vec::new())
+            }
+            PopDest::Temp => Self::write_fixed(&Self::temp_name(index), Vec::new()),
+            PopDest::Pointer => Self::write_fixed(&Self::pointer_name(index), Vec::new()),
+            PopDest::Constant => unreachable!("popping into constant is rejected by Ast::validate"),
+        }
+    }
+
+    /// Translates the statement for the cached lowering, where the top of the stack is kept
+    /// in D instead of RAM while `cached` is set. Statements that are not worth handling
+    /// specially spill it and are translated as usual.
+    fn compile_cached(
+        &self,
+        lg: &mut LabelGenerator,
+        shared: &[Routine],
+        cached: &mut bool,
+    ) -> Vec<Instruction> {
+        let command = |compute, target, jump| Instruction::Command {
+            compute,
+            target,
+            jump,
+        };
+        let load = |label: &str| Instruction::Load {
+            data: LoadData::label(label),
+        };
+        // Takes the top of the stack into D and the value below it into M
+        let take2 = |cached: &mut bool| {
+            let mut out = Self::take(cached);
             out.extend([
+                load("SP"),
+                command(Compute::MminOne, Target::A | Target::M, Jump::NONE),
+            ]);
+            out
+        };
+        let binary = match self {
+            Statement::Add => Some(Compute::DplusM),
+            Statement::Sub => Some(Compute::MminD),
+            Statement::And => Some(Compute::DandM),
+            Statement::Or => Some(Compute::DorM),
+            _ => None,
+        };
+        let compare = match self {
+            Statement::Eq => Some(Jump::JEQ),
+            Statement::Lt => Some(Jump::JLT),
+            Statement::Gt => Some(Jump::JGT),
+            _ => None,
+        }
+        .filter(|_| !shared.contains(&Routine::Compare));
+
+        // Whether the statement leaves its result in D
+        let (out, result) = match (self, binary, compare) {
+            (_, Some(compute), _) => {
+                let mut out = take2(cached);
+                out.push(command(compute, Target::D, Jump::NONE));
+                (out, true)
+            }
+            (_, _, Some(jump)) => {
+                let truelabel = lg.next_statement();
+                let endlabel = lg.next_statement();
+                let mut out = take2(cached);
+                out.extend([
+                    command(Compute::MminD, Target::D, Jump::NONE),
+                    load(&truelabel),
+                    command(Compute::D, Target::empty(), jump),
+                    command(Compute::Zero, Target::D, Jump::NONE),
+                    load(&endlabel),
+                    command(Compute::Zero, Target::empty(), Jump::JMP),
+                    Instruction::label(&truelabel),
+                    command(Compute::NegOne, Target::D, Jump::NONE),
+                    Instruction::label(&endlabel),
+                ]);
+                (out, true)
+            }
+            (Statement::Neg, _, _) if *cached => {
+                (vec![command(Compute::NegD, Target::D, Jump::NONE)], true)
+            }
+            (Statement::Not, _, _) if *cached => {
+                (vec![command(Compute::NotD, Target::D, Jump::NONE)], true)
+            }
+            (Statement::Push(source, i), _, _) => {
+                let mut out = Self::spill(cached);
+                out.append(&mut Self::read(source, *i));
+                (out, true)
+            }
+            (Statement::Pop(dest, i), _, _) => {
+                let mut out = Self::take(cached);
+                out.append(&mut Self::write_d(dest, *i));
+                (out, false)
+            }
+            (Statement::IfGoto(l), _, _) => {
+                let mut out = Self::take(cached);
+                out.extend([
+                    Instruction::Load {
+                        data: LoadData::Label(lg.scoped_label(l)),
+                    },
+                    command(Compute::D, Target::empty(), Jump::JNE),
+                ]);
+                (out, false)
+            }
+            (Statement::IfCompare(jump, l), _, _) => {
+                let mut out = take2(cached);
+                out.extend([
+                    command(Compute::MminD, Target::D, Jump::NONE),
+                    Instruction::Load {
+                        data: LoadData::Label(lg.scoped_label(l)),
+                    },
+                    command(Compute::D, Target::empty(), jump.clone()),
+                ]);
+                (out, false)
+            }
+            (Statement::IfNotGoto(l), _, _) => {
+                let mut out = Self::take(cached);
+                out.extend([
+                    command(Compute::DplusOne, Target::D, Jump::NONE),
+                    Instruction::Load {
+                        data: LoadData::Label(lg.scoped_label(l)),
+                    },
+                    command(Compute::D, Target::empty(), Jump::JNE),
+                ]);
+                (out, false)
+            }
+            _ => {
+                let mut out = Self::spill(cached);
+                out.append(&mut self.compile(lg, shared));
+                (out, false)
+            }
+        };
+        *cached = result;
+        out
+    }
+
+    /// Translates the statement, into a use of its shared routine if that is enabled.
+    pub fn compile(&self, lg: &mut LabelGenerator, shared: &[Routine]) -> Vec<Instruction> {
+        if let Some((routine, label)) = self.routine()
+            && shared.contains(&routine)
+        {
+            return self.use_routine(lg, label);
+        }
+        match self {
+            Statement::Not => Self::compute1(Compute::NotM),
+            Statement::And => Self::compute2(Compute::DandM),
+            Statement::Or => Self::compute2(Compute::DorM),
+            Statement::Neg => Self::compute1(Compute::NegM),
+            Statement::Add => Self::compute2(Compute::DplusM),
+            Statement::Sub => Self::compute2(Compute::MminD),
+            Statement::Eq => Self::cmp(lg, Jump::JEQ),
+            Statement::Lt => Self::cmp(lg, Jump::JLT),
+            Statement::Gt => Self::cmp(lg, Jump::JGT),
+
+            Statement::Push(source, i) => {
+                let mut out = Self::read(source, *i);
+                out.append(&mut Self::push_d());
+                out
+            }
+            Statement::Pop(dest, i) => Self::write(dest, *i, Self::pop(Target::D)),
+            Statement::Move(source, i, dest, j) => Self::write(dest, *j, Self::read(source, *i)),
+            Statement::Label(l) => [Instruction::Label {
+                label: lg.scoped_label(l),
+            }]
+            .to_vec(),
+            Statement::Goto(l) => [
+                Instruction::Load {
+                    data: LoadData::Label(lg.scoped_label(l)),
+                },
+                Instruction::Command {
+                    compute: Compute::Zero,
+                    target: Target::empty(),
+                    jump: Jump::JMP,
+                },
+            ]
+            .to_vec(),
+            Statement::IfGoto(l) => {
+                let mut out = Self::pop(Target::D);
+                out.extend([
+                    Instruction::Load {
+                        data: LoadData::Label(lg.scoped_label(l)),
+                    },
+                    Instruction::Command {
+                        compute: Compute::D,
+                        target: Target::empty(),
+                        jump: Jump::JNE,
+                    },
+                ]);
+                out
+            }
+            Statement::IfCompare(jump, l) => {
+                let mut out = Self::pop(Target::D);
+                out.extend([
+                    Instruction::Load {
+                        data: LoadData::label("SP"),
+                    },
+                    Instruction::Command {
+                        compute: Compute::MminOne,
+                        target: Target::A | Target::M,
+                        jump: Jump::NONE,
+                    },
+                    Instruction::Command {
+                        compute: Compute::MminD,
+                        target: Target::D,
+                        jump: Jump::NONE,
+                    },
+                    Instruction::Load {
+                        data: LoadData::Label(lg.scoped_label(l)),
+                    },
+                    Instruction::Command {
+                        compute: Compute::D,
+                        target: Target::empty(),
+                        jump: jump.clone(),
+                    },
+                ]);
+                out
+            }
+            // The negation is non-zero unless the value is -1
+            Statement::IfNotGoto(l) => [
+                Instruction::Load {
+                    data: LoadData::label("SP"),
+                },
+                Instruction::Command {
+                    compute: Compute::MminOne,
+                    target: Target::A | Target::M,
+                    jump: Jump::NONE,
+                },
+                Instruction::Command {
+                    compute: Compute::MplusOne,
+                    target: Target::D,
+                    jump: Jump::NONE,
+                },
+                Instruction::Load {
+                    data: LoadData::Label(lg.scoped_label(l)),
+                },
+                Instruction::Command {
+                    compute: Compute::D,
+                    target: Target::empty(),
+                    jump: Jump::JNE,
+                },
+            ]
+            .to_vec(),
+            Statement::Call(function, args) => {
+                let retlabel = lg.next_return();
+                let mut out = [
+                    Instruction::Load {
+                        data: LoadData::label(&retlabel),
+                    },
+                    Instruction::Command {
+                        compute: Compute::A,
+                        target: Target::D,
+                        jump: Jump::NONE,
+                    },
+                ]
+                .to_vec();
+                out.append(&mut Self::push_frame());
+                out.extend([
+                    // ARG = SP - 5 - args
+                    Instruction::Load {
+                        data: LoadData::label("SP"),
+                    },
+                    Instruction::Command {
+                        compute: Compute::M,
+                        target: Target::D,
+                        jump: Jump::NONE,
+                    },
+                    Instruction::Load {
+                        data: LoadData::Data(5 + args),
+                    },
+                    Instruction::Command {
+                        compute: Compute::DminA,
+                        target: Target::D,
+                        jump: Jump::NONE,
+                    },
+                    Instruction::Load {
+                        data: LoadData::label("ARG"),
+                    },
+                    Instruction::Command {
+                        compute: Compute::D,
+                        target: Target::M,
+                        jump: Jump::NONE,
+                    },
+                    // LCL = SP
+                    Instruction::Load {
+                        data: LoadData::label("SP"),
+                    },
+                    Instruction::Command {
+                        compute: Compute::M,
+                        target: Target::D,
+                        jump: Jump::NONE,
+                    },
+                    Instruction::Load {
+                        data: LoadData::label("LCL"),
+                    },
+                    Instruction::Command {
+                        compute: Compute::D,
+                        target: Target::M,
+                        jump: Jump::NONE,
+                    },
+                    Instruction::Load {
+                        data: LoadData::Label(Function::function_label(function)),
+                    },
+                    Instruction::Command {
+                        compute: Compute::Zero,
+                        target: Target::empty(),
+                        jump: Jump::JMP,
+                    },
+                    Instruction::Label { label: retlabel },
+                ]);
+                out
+            }
+            Statement::Return => Self::return_from_function(),
+        }
+    }
+
+    /// Returns from the current function to the address saved in its frame.
+    fn return_from_function() -> Vec<Instruction> {
+        // Save the return address in R15 first. If the function has no arguments it is
+        // stored at ARG[0] and would be overwritten by the return value.
+        let mut out = [
+            Instruction::Load {
+                data: LoadData::label("LCL"),
+            },
+            Instruction::Command {
+                compute: Compute::M,
+                target: Target::D,
+                jump: Jump::NONE,
+            },
+            Instruction::Load {
+                data: LoadData::Data(5),
+            },
+            Instruction::Command {
+                compute: Compute::DminA,
+                target: Target::A,
+                jump: Jump::NONE,
+            },
+            Instruction::Command {
+                compute: Compute::M,
+                target: Target::D,
+                jump: Jump::NONE,
+            },
+            Instruction::Load {
+                data: LoadData::label("R15"),
+            },
+            Instruction::Command {
+                compute: Compute::D,
+                target: Target::M,
+                jump: Jump::NONE,
+            },
+        ]
+        .to_vec();
+        // Move the return value on the stack to ARG[0] which will be top of stack later
+        out.append(&mut Self::pop_common("ARG", 0));
+        out.extend([
+            // Reset the top of the stack to be at current ARG[1]
+            Instruction::Load {
+                data: LoadData::label("ARG"),
+            },
+            Instruction::Command {
+                compute: Compute::MplusOne,
+                target: Target::D,
+                jump: Jump::NONE,
+            },
+            Instruction::Load {
+                data: LoadData::label("SP"),
+            },
+            Instruction::Command {
+                compute: Compute::D,
+                target: Target::M,
+                jump: Jump::NONE,
+            },
+            // Restore Segment Pointers R14 is scratch memory of current recovery pointer
+            Instruction::Load {
+                data: LoadData::label("LCL"),
+            },
+            Instruction::Command {
+                compute: Compute::MminOne,
+                target: Target::D,
+                jump: Jump::NONE,
+            },
+            // D now contains the "saved THAT" address. we save it first in R14 before
+            // continuing
+            Instruction::Load {
+                data: LoadData::label("R14"),
+            },
+            Instruction::Command {
+                compute: Compute::D,
+                target: Target::A | Target::M,
+                jump: Jump::NONE,
+            },
+            // Now restore THAT
+            Instruction::Command {
+                compute: Compute::M,
+                target: Target::D,
+                jump: Jump::NONE,
+            },
+            Instruction::Load {
+                data: LoadData::label("THAT"),
+            },
+            Instruction::Command {
+                compute: Compute::D,
+                target: Target::M,
+                jump: Jump::NONE,
+            },
+            // Now the same for "saved THIS" which is -1 again.
+            Instruction::Load {
+                data: LoadData::label("R14"),
+            },
+            Instruction::Command {
+                compute: Compute::MminOne,
+                target: Target::A | Target::M,
+                jump: Jump::NONE,
+            },
+            Instruction::Command {
+                compute: Compute::M,
+                target: Target::D,
+                jump: Jump::NONE,
+            },
+            Instruction::Load {
+                data: LoadData::label("THIS"),
+            },
+            Instruction::Command {
+                compute: Compute::D,
+                target: Target::M,
+                jump: Jump::NONE,
+            },
+            // Now the same for "saved ARG" which is -1 again.
+            Instruction::Load {
+                data: LoadData::label("R14"),
+            },
+            Instruction::Command {
+                compute: Compute::MminOne,
+                target: Target::A | Target::M,
+                jump: Jump::NONE,
+            },
+            Instruction::Command {
+                compute: Compute::M,
+                target: Target::D,
+                jump: Jump::NONE,
+            },
+            Instruction::Load {
+                data: LoadData::label("ARG"),
+            },
+            Instruction::Command {
+                compute: Compute::D,
+                target: Target::M,
+                jump: Jump::NONE,
+            },
+            // Now the same for "saved LCL" which is -1 again.
+            Instruction::Load {
+                data: LoadData::label("R14"),
+            },
+            Instruction::Command {
+                compute: Compute::MminOne,
+                target: Target::A | Target::M,
+                jump: Jump::NONE,
+            },
+            Instruction::Command {
+                compute: Compute::M,
+                target: Target::D,
+                jump: Jump::NONE,
+            },
+            Instruction::Load {
+                data: LoadData::label("LCL"),
+            },
+            Instruction::Command {
+                compute: Compute::D,
+                target: Target::M,
+                jump: Jump::NONE,
+            },
+            // Jump to the return address we saved at the start
+            Instruction::Load {
+                data: LoadData::label("R15"),
+            },
+            Instruction::Command {
+                compute: Compute::M,
+                target: Target::A,
+                jump: Jump::NONE,
+            },
+            Instruction::Command {
+                compute: Compute::Zero,
+                target: Target::empty(),
+                jump: Jump::JMP,
+            },
+        ]);
+        out
+    }
+}
+
+impl Function {
+    fn function_label(name: &str) -> String {
+        format!("{}{}", FUNCTION_PREFIX, name)
+    }
+
+    pub fn compile(
+        &self,
+        lg: &mut LabelGenerator,
+        shared: &[Routine],
+        lowering: Lowering,
+        out: &mut Assembly,
+        file: &Rc<str>,
+    ) {
+        lg.set_function(&self.name);
+        let mut prologue = [
+            Instruction::Label {
+                label: Self::function_label(&self.name),
+            },
+            Instruction::Load {
+                data: LoadData::label("LCL"),
+            },
+            Instruction::Command {
+                compute: Compute::M,
+                target: Target::A,
+                jump: Jump::NONE,
+            },
+        ]
+        .to_vec();
+        for _ in 0..self.locals {
+            prologue.extend([
                 Instruction::Command {
                     compute: Compute::Zero,
                     target: Target::M,
@@ -539,10 +1205,47 @@ impl Function {
                 },
             ]);
         }
-
-        for statement in &self.statements {
-            out.append(&mut statement.compile(lg));
+        if self.locals > 0 {
+            // A now points behind the last local which is where the stack of the function starts
+            prologue.extend([
+                Instruction::Command {
+                    compute: Compute::A,
+                    target: Target::D,
+                    jump: Jump::NONE,
+                },
+                Instruction::Load {
+                    data: LoadData::label("SP"),
+                },
+                Instruction::Command {
+                    compute: Compute::D,
+                    target: Target::M,
+                    jump: Jump::NONE,
+                },
+            ]);
         }
-        out
+
+        out.extend(prologue, Some(&Location::new(file, self.span.into_range())));
+        compile_statements(&self.statements, lg, shared, lowering, out, file);
+    }
+}
+
+/// Translates a sequence of statements with the given lowering.
+pub fn compile_statements(
+    statements: &[Spanned<Statement>],
+    lg: &mut LabelGenerator,
+    shared: &[Routine],
+    lowering: Lowering,
+    out: &mut Assembly,
+    file: &Rc<str>,
+) {
+    let mut cached = false;
+    for (statement, span) in statements {
+        let instructions = match lowering {
+            Lowering::Stack => statement.compile(lg, shared),
+            Lowering::Cached => statement.compile_cached(lg, shared, &mut cached),
+        };
+        out.extend(instructions, Some(&Location::new(file, span.into_range())));
     }
+    // Whatever comes next expects the whole stack in RAM
+    out.extend(Statement::spill(&mut cached), None);
 }
@@ -1,14 +1,26 @@
-use std::{collections::HashSet, fs::File, io::Read, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fs::{File, read_dir},
+    io::Read,
+    path::Path,
+    rc::Rc,
+};
 
-use ariadne::{Color, Label, Report, ReportKind, sources};
-use chumsky::{Parser, error::Rich};
+use chumsky::Parser;
+use clap::ValueEnum;
 use compiler::LabelGenerator;
 use parser::Span;
 
-use crate::{CodeType, assembly::Assembly};
+use crate::{
+    CodeType,
+    assembly::{Assembly, Jump, Report},
+    diagnostic::{Diagnostic, Location},
+};
 
 mod compiler;
+mod optimizer;
 mod parser;
+mod validate;
 
 #[derive(Debug, Clone)]
 enum PushSource {
@@ -31,6 +43,8 @@ enum PopDest {
     That,
     Temp,
     Pointer,
+    /// Only parsed, validation rejects it as the constant segment is read-only
+    Constant,
 }
 
 #[derive(Debug, Clone)]
@@ -54,115 +68,498 @@ enum Statement {
     Goto(String),
     IfGoto(String),
 
+    Call(String, u16),
+    Return,
+
+    // Only produced by the optimizer
+    /// `push` directly followed by `pop`, copying the value without going through the stack
+    Move(PushSource, u16, PopDest, u16),
+    /// A comparison followed by `if-goto`, jumping if the difference of the two values on
+    /// top of the stack satisfies the condition
+    IfCompare(Jump, String),
+    /// `not` followed by `if-goto`
+    IfNotGoto(String),
+}
+
+/// Constructs that can be translated into a jump to a routine shared by the whole program,
+/// instead of being inlined wherever they are used.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Routine {
+    /// `eq`, `lt` and `gt`
+    Compare,
+    Call,
     Return,
 }
 
+/// How statements are translated into assembly.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Lowering {
+    /// Every value lives on the stack in RAM
+    #[default]
+    Stack,
+    /// The top of the stack is kept in D between statements, and only written to RAM before
+    /// labels, jumps and calls
+    Cached,
+}
+
+type Spanned<T> = (T, Span);
+
 #[derive(Debug, Clone)]
 struct Function {
     name: String,
     locals: u16,
-    statements: Vec<Statement>,
+    statements: Vec<Spanned<Statement>>,
+    /// The span of the function declaration
+    span: Span,
 }
 
+/// Statements outside any function come first and run from wherever control enters the
+/// module, usually the start of the program.
 #[derive(Debug, Clone)]
-enum Ast {
-    Statements(Vec<Statement>),
-    SingleFile(Vec<Function>),
+struct Ast {
+    statements: Vec<Spanned<Statement>>,
+    functions: Vec<Function>,
 }
 
 #[derive(Debug)]
-pub struct VM {
+struct Module {
+    file: Rc<str>,
     ast: Ast,
     label_generator: LabelGenerator,
 }
 
+#[derive(Debug)]
+pub struct VM {
+    modules: Vec<Module>,
+    bootstrap: bool,
+    optimize: bool,
+    shared: Vec<Routine>,
+    lowering: Lowering,
+}
+
 impl VM {
-    pub fn from_file(path: &PathBuf) -> Result<Self, String> {
-        let mut src = String::new();
-        File::open(&path)
-            .map_err(|e| e.to_string())?
-            .read_to_string(&mut src)
-            .map_err(|e| e.to_string())?;
-        let src2 = src.clone();
-        let (out, errs) = match src.contains("function ") {
-            false => {
-                let (out, errs) = parser::statements(path.file_name().unwrap().to_str().unwrap())
-                    .parse(&src2)
-                    .into_output_errors();
-                (out.map(|s| Ast::Statements(s)), errs)
+    pub fn from_file(path: &Path) -> Result<Self, Vec<Diagnostic>> {
+        Ok(VM {
+            modules: vec![Module::from_file(path)?],
+            bootstrap: false,
+            optimize: false,
+            shared: Vec::new(),
+            lowering: Lowering::Stack,
+        })
+    }
+
+    /// Loads all .vm files in a directory so they can be translated into a single program.
+    /// Bootstrap code is enabled by default.
+    pub fn from_dir(path: &Path) -> Result<Self, Vec<Diagnostic>> {
+        let mut files = Vec::new();
+        for entry in read_dir(path).map_err(|e| Diagnostic::io(path, e))? {
+            let file = entry.map_err(|e| Diagnostic::io(path, e))?.path();
+            if file.is_file() && file.extension().is_some_and(|e| e == "vm") {
+                files.push(file);
             }
-            true => {
-                let (out, errs) = parser::functions(path.file_name().unwrap().to_str().unwrap())
-                    .parse(&src2)
-                    .into_output_errors();
-                (out.map(|f| Ast::SingleFile(f)), errs)
+        }
+        if files.is_empty() {
+            return Err(Diagnostic::error(
+                "no-input",
+                format!(
+                    "Directory {} does not contain any .vm files",
+                    path.to_string_lossy()
+                ),
+            )
+            .into());
+        }
+        files.sort();
+
+        let mut modules = Vec::new();
+        let mut errors = Vec::new();
+        for file in files {
+            match Module::from_file(&file) {
+                Ok(m) => modules.push(m),
+                Err(mut e) => errors.append(&mut e),
             }
-        };
-        println!("Parse output: {:?}", out);
-        if !errs.is_empty() {
-            let filename = path.to_str().unwrap().to_string();
-            Self::print_err(errs, filename, src);
-            return Err("Failed to compile".to_string());
+        }
+        if !errors.is_empty() {
+            return Err(errors);
         }
         Ok(VM {
+            modules,
+            bootstrap: true,
+            optimize: false,
+            shared: Vec::new(),
+            lowering: Lowering::Stack,
+        })
+    }
+
+    pub fn set_bootstrap(&mut self, bootstrap: bool) {
+        self.bootstrap = bootstrap;
+    }
+
+    /// Optimizes the VM code before translating it, and runs the peephole optimizer over
+    /// the generated assembly.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    /// Translates the given constructs into jumps to shared routines.
+    pub fn set_shared(&mut self, shared: Vec<Routine>) {
+        self.shared = shared;
+    }
+
+    pub fn set_lowering(&mut self, lowering: Lowering) {
+        self.lowering = lowering;
+    }
+
+    /// The syntax tree of every module, for debugging the parser.
+    pub fn dump_ast(&self) -> String {
+        self.modules
+            .iter()
+            .map(|module| format!("{}: {:#?}\n", module.file, module.ast))
+            .collect()
+    }
+
+    /// Translates the program into assembly. When optimizing, also returns what the peephole
+    /// optimizer saved.
+    pub fn compile(mut self) -> Result<(CodeType, Option<Report>), Vec<Diagnostic>> {
+        let errors: Vec<_> = self.modules.iter().flat_map(Module::check).collect();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        if self.optimize {
+            for module in &mut self.modules {
+                module.optimize();
+            }
+        }
+
+        let mut out = Assembly::default();
+        if self.bootstrap {
+            out.extend(compiler::bootstrap(), None);
+        }
+        let used: Vec<_> = self
+            .modules
+            .iter()
+            .flat_map(Module::statements)
+            .filter(|s| s.routine().is_some_and(|(r, _)| self.shared.contains(&r)))
+            .collect();
+        out.extend(compiler::runtime(&used, self.bootstrap), None);
+        for module in self.modules {
+            out.append(&mut module.compile(&self.shared, self.lowering));
+        }
+        let report = self.optimize.then(|| out.optimize());
+        Ok((CodeType::Assembly(out), report))
+    }
+}
+
+impl Module {
+    fn from_file(path: &Path) -> Result<Self, Vec<Diagnostic>> {
+        let mut src = String::new();
+        File::open(path)
+            .map_err(|e| Diagnostic::io(path, e))?
+            .read_to_string(&mut src)
+            .map_err(|e| Diagnostic::io(path, e))?;
+        Self::from_source(&src, path)
+    }
+
+    fn from_source(src: &str, path: &Path) -> Result<Self, Vec<Diagnostic>> {
+        let file: Rc<str> = path.to_string_lossy().into();
+        // The file name prefixes static variables and labels
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            return Err(vec![Diagnostic::error(
+                "invalid-path",
+                format!("{}: the file name is missing or not valid UTF-8", file),
+            )]);
+        };
+        let (out, errs) = parser::module(name).parse(src).into_output_errors();
+        let mut errors: Vec<_> = errs
+            .into_iter()
+            .map(|e| Diagnostic::parse(e, &file))
+            .collect();
+        if let Some(ast) = &out {
+            errors.append(&mut ast.validate(src, &file));
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Module {
+            file,
             ast: out.unwrap(),
-            label_generator: LabelGenerator::new(path),
+            label_generator: LabelGenerator::new(name),
         })
     }
 
-    fn print_err<'a>(errs: Vec<Rich<'a, char, Span>>, filename: String, src: String) {
-        errs.into_iter()
-            .map(|e| e.map_token(|c| c.to_string()))
-            .for_each(|e| {
-                Report::build(ReportKind::Error, (filename.clone(), e.span().into_range()))
-                    .with_config(ariadne::Config::new().with_index_type(ariadne::IndexType::Byte))
-                    .with_message(e.to_string())
-                    .with_label(
-                        Label::new((filename.clone(), e.span().into_range()))
-                            .with_message(e.reason().to_string())
-                            .with_color(Color::Red),
-                    )
-                    .with_labels(e.contexts().map(|(label, span)| {
-                        Label::new((filename.clone(), span.into_range()))
-                            .with_message(format!("while parsing this {}", label))
-                            .with_color(Color::Yellow)
-                    }))
-                    .finish()
-                    .print(sources([(filename.clone(), src.clone())]))
-                    .unwrap()
-            });
-    }
-
-    pub fn compile(mut self) -> Result<CodeType, String> {
-        let mut out = Vec::new();
-
-        let mut labels = HashSet::new();
-        let statementiter: Box<dyn Iterator<Item = &Statement>> = match &self.ast {
-            Ast::Statements(s) => Box::new(s.iter()),
-            Ast::SingleFile(f) => Box::new(f.iter().map(|f| f.statements.iter()).flatten()),
+    /// Labels are scoped to their function. Checks that every label is defined only once and
+    /// that every jump targets a label defined in the same function.
+    fn check_labels(
+        &self,
+        statements: &[Spanned<Statement>],
+        function: Option<&str>,
+    ) -> Vec<Diagnostic> {
+        let scope = match function {
+            Some(f) => format!(" in function '{}'", f),
+            None => String::new(),
         };
-        for s in statementiter {
+        let mut errors = Vec::new();
+        let mut labels: HashMap<&String, std::ops::Range<usize>> = HashMap::new();
+        for (s, span) in statements {
             if let Statement::Label(l) = s {
-                if labels.contains(&l) {
-                    return Err(format!("Duplicate Label definition '{}'", l));
+                let location = Location::new(&self.file, span.into_range());
+                if let Some(first) = labels.get(l) {
+                    errors.push(
+                        Diagnostic::error(
+                            "duplicate-label",
+                            format!("Duplicate Label definition '{}'{}", l, scope),
+                        )
+                        .with_location(location)
+                        .with_label("defined again here")
+                        .with_secondary(
+                            Location::new(&self.file, first.clone()),
+                            "first defined here",
+                        ),
+                    );
+                } else {
+                    labels.insert(l, span.into_range());
                 }
-                labels.insert(l);
             }
         }
-
-        match self.ast {
-            Ast::Statements(statements) => {
-                for statement in statements {
-                    out.append(&mut statement.compile(&mut self.label_generator));
-                }
+        for (s, span) in statements {
+            if let Statement::Goto(l) | Statement::IfGoto(l) = s
+                && !labels.contains_key(l)
+            {
+                errors.push(
+                    Diagnostic::error(
+                        "undefined-label",
+                        format!("Jump to undefined Label '{}'{}", l, scope),
+                    )
+                    .with_location(Location::new(&self.file, span.into_range()))
+                    .with_note("labels are only visible inside the function defining them"),
+                );
             }
-            Ast::SingleFile(functions) => {
-                for function in functions {
-                    out.append(&mut function.compile(&mut self.label_generator));
-                }
+        }
+        errors
+    }
+
+    fn statements(&self) -> Vec<&Statement> {
+        let functions = self.ast.functions.iter().flat_map(|f| &f.statements);
+        self.ast
+            .statements
+            .iter()
+            .chain(functions)
+            .map(|(s, _)| s)
+            .collect()
+    }
+
+    /// Checks the labels of the top-level statements and of every function.
+    fn check(&self) -> Vec<Diagnostic> {
+        let mut errors = self.check_labels(&self.ast.statements, None);
+        for function in &self.ast.functions {
+            errors.append(&mut self.check_labels(&function.statements, Some(&function.name)));
+        }
+        errors
+    }
+
+    fn optimize(&mut self) {
+        optimizer::optimize(&mut self.ast.statements);
+        for function in &mut self.ast.functions {
+            optimizer::optimize(&mut function.statements);
+        }
+    }
+
+    fn compile(mut self, shared: &[Routine], lowering: Lowering) -> Assembly {
+        let mut out = Assembly::default();
+        compiler::compile_statements(
+            &self.ast.statements,
+            &mut self.label_generator,
+            shared,
+            lowering,
+            &mut out,
+            &self.file,
+        );
+        for function in self.ast.functions {
+            function.compile(
+                &mut self.label_generator,
+                shared,
+                lowering,
+                &mut out,
+                &self.file,
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use crate::{CodeType, assembly::linker, cpu::Cpu};
+
+    use super::{Lowering, Module, Routine, VM};
+
+    /// Translates and runs the program, returning the CPU and the number of instructions.
+    fn run(dir: &Path, bootstrap: bool, shared: &[Routine], lowering: Lowering) -> (Cpu, usize) {
+        let mut vm = VM::from_dir(dir).unwrap();
+        vm.set_bootstrap(bootstrap);
+        vm.set_shared(shared.to_vec());
+        vm.set_lowering(lowering);
+        let Ok((CodeType::Assembly(assembly), _)) = vm.compile() else {
+            panic!("translating {} failed", dir.to_string_lossy());
+        };
+        let (hex, _) = linker::link(&[assembly.object().unwrap()]).unwrap();
+        let size = hex.instructions.len();
+        let mut cpu = Cpu::new(&hex);
+        for (register, value) in [256, 300, 400, 3000, 3010].into_iter().enumerate() {
+            cpu.ram[register] = value;
+        }
+        cpu.run(50_000);
+        (cpu, size)
+    }
+
+    /// Checks that two runs of a program leave the same registers, statics and stack.
+    fn assert_same(expected: &Cpu, actual: &Cpu, bootstrap: bool, name: &str) {
+        // R13 to R15 are scratch registers
+        assert_eq!(expected.ram[..13], actual.ram[..13], "{}", name);
+        assert_eq!(expected.ram[16..256], actual.ram[16..256], "{}", name);
+        // Frames on the stack hold return addresses, which move with the code
+        let sp = expected.ram[0] as usize;
+        let stack = match bootstrap {
+            true => sp - 1..sp,
+            false => 256..sp,
+        };
+        assert_eq!(expected.ram[stack.clone()], actual.ram[stack], "{}", name);
+    }
+
+    /// Checks that every combination of shared routines computes what the inlined code does.
+    /// Returns the program sizes, inlined first.
+    fn check(dir: &Path, bootstrap: bool) -> Vec<usize> {
+        let (expected, size) = run(dir, bootstrap, &[], Lowering::Stack);
+        let mut sizes = vec![size];
+        for shared in [
+            &[Routine::Compare][..],
+            &[Routine::Call],
+            &[Routine::Return],
+            &[Routine::Call, Routine::Return],
+            &[Routine::Compare, Routine::Call, Routine::Return],
+        ] {
+            let (actual, size) = run(dir, bootstrap, shared, Lowering::Stack);
+            let name = format!("{} {:?}", dir.to_string_lossy(), shared);
+            assert_same(&expected, &actual, bootstrap, &name);
+            sizes.push(size);
+        }
+        sizes
+    }
+
+    #[test]
+    fn shared_routines() {
+        let projects = Path::new(env!("CARGO_MANIFEST_DIR")).join("../projects");
+        let sizes = check(&projects.join("7/StackArithmetic/StackTest"), false);
+        assert!(sizes[1] < sizes[0], "sharing comparisons saves code");
+        for dir in ["FibonacciElement", "NestedCall", "StaticsTest"] {
+            let sizes = check(&projects.join("8/FunctionCalls").join(dir), true);
+            assert!(
+                sizes[5] < sizes[0],
+                "sharing everything saves code in {}",
+                dir
+            );
+        }
+    }
+
+    #[test]
+    fn mixed_constructs() {
+        // Comparisons inside recursive calls, so every construct is used in every mix
+        let dir = std::env::temp_dir().join(format!("shared-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("Sys.vm"),
+            "function Sys.init 0
+            push constant 6
+            call Sys.count 1
+            pop static 0
+            push constant 3
+            push constant 5
+            gt
+            pop static 1
+            push constant 4
+            push constant 4
+            eq
+            pop static 2
+            label END
+            goto END
+            function Sys.count 0
+            push argument 0
+            push constant 1
+            lt
+            if-goto DONE
+            push argument 0
+            push constant 1
+            sub
+            call Sys.count 1
+            push argument 0
+            push constant 3
+            lt
+            add
+            return
+            label DONE
+            push constant 0
+            return
+            ",
+        )
+        .unwrap();
+        check(&dir, true);
+        let (cpu, _) = run(&dir, true, &[Routine::Compare], Lowering::Stack);
+        fs::remove_dir_all(&dir).unwrap();
+        // Counts 1 and 2 as less than 3, as -1 each
+        assert_eq!(cpu.ram[16..19], [0xFFFE, 0, 0xFFFF]);
+    }
+
+    #[test]
+    fn cached_lowering() {
+        let projects = Path::new(env!("CARGO_MANIFEST_DIR")).join("../projects");
+        for (dir, bootstrap) in [
+            ("7/StackArithmetic/SimpleAdd", false),
+            ("7/StackArithmetic/StackTest", false),
+            ("7/MemoryAccess/BasicTest", false),
+            ("7/MemoryAccess/PointerTest", false),
+            ("7/MemoryAccess/StaticTest", false),
+            ("8/ProgramFlow/BasicLoop", false),
+            ("8/FunctionCalls/FibonacciElement", true),
+            ("8/FunctionCalls/NestedCall", true),
+            ("8/FunctionCalls/StaticsTest", true),
+        ] {
+            let dir = projects.join(dir);
+            let (expected, _) = run(&dir, bootstrap, &[], Lowering::Stack);
+            for shared in [&[][..], &[Routine::Compare, Routine::Call, Routine::Return]] {
+                let (actual, _) = run(&dir, bootstrap, shared, Lowering::Cached);
+                let name = format!("{} cached {:?}", dir.to_string_lossy(), shared);
+                assert_same(&expected, &actual, bootstrap, &name);
             }
         }
+    }
+
+    #[test]
+    fn labels_outside_functions() {
+        let dir = std::env::temp_dir().join(format!("labels-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("A.vm"),
+            "push constant 3\npop temp 0\nlabel LOOP\npush temp 0\npush constant 1\nsub\n\
+             pop temp 0\npush temp 0\nif-goto LOOP\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("B.vm"),
+            "push constant 2\npop temp 1\nlabel LOOP\npush temp 1\npush constant 1\nsub\n\
+             pop temp 1\npush temp 1\nif-goto LOOP\nlabel END\ngoto END\n",
+        )
+        .unwrap();
+        let (cpu, _) = run(&dir, false, &[], Lowering::Stack);
+        fs::remove_dir_all(&dir).unwrap();
+        // Each file loops on its own label until its counter reaches 0
+        assert_eq!(cpu.ram[5..7], [0, 0]);
+        assert_eq!(cpu.ram[0], 256);
+    }
 
-        Ok(CodeType::Assembly(Assembly::from_instructions(out)))
+    #[test]
+    fn invalid_path() {
+        let errors = Module::from_source("push constant 1\n", Path::new("..")).unwrap_err();
+        assert_eq!(errors[0].code, "invalid-path");
     }
 }
@@ -0,0 +1,476 @@
+use crate::assembly::Jump;
+
+use super::{PushSource, Spanned, Statement};
+
+/// Rewrites the statements of one function into fewer, equivalent ones, until no pass finds
+/// anything left to improve.
+pub fn optimize(statements: &mut Vec<Spanned<Statement>>) {
+    loop {
+        let mut changed = false;
+        for pass in [
+            fold_constants,
+            cancel_nots,
+            fuse_branches,
+            shortcut_stack,
+            remove_unreachable,
+        ] {
+            changed |= pass(statements);
+        }
+        if !changed {
+            return;
+        }
+    }
+}
+
+/// Replaces the statements at `i..i + len` by others spanning all of them. Returns whether
+/// the replacement is shorter.
+fn replace(
+    statements: &mut Vec<Spanned<Statement>>,
+    i: usize,
+    len: usize,
+    with: Vec<Statement>,
+) -> bool {
+    if with.len() >= len {
+        return false;
+    }
+    let span = (statements[i].1.start..statements[i + len - 1].1.end).into();
+    statements.splice(i..i + len, with.into_iter().map(|s| (s, span)));
+    true
+}
+
+/// The statements pushing a value, `push constant` only takes 0 to 32767.
+fn push_value(value: u16) -> Vec<Statement> {
+    if value <= i16::MAX as u16 {
+        vec![Statement::Push(PushSource::Constant, value)]
+    } else if value.wrapping_neg() <= i16::MAX as u16 {
+        vec![
+            Statement::Push(PushSource::Constant, value.wrapping_neg()),
+            Statement::Neg,
+        ]
+    } else {
+        vec![
+            Statement::Push(PushSource::Constant, !value),
+            Statement::Not,
+        ]
+    }
+}
+
+/// The result of an arithmetic or logical statement on constants, computed like the CPU.
+fn evaluate(statement: &Statement, x: u16, y: u16) -> Option<u16> {
+    let bool = |b| match b {
+        true => u16::MAX,
+        false => 0,
+    };
+    // Comparisons look at the sign of the difference, overflow included
+    let difference = x.wrapping_sub(y) as i16;
+    match statement {
+        Statement::Add => Some(x.wrapping_add(y)),
+        Statement::Sub => Some(x.wrapping_sub(y)),
+        Statement::And => Some(x & y),
+        Statement::Or => Some(x | y),
+        Statement::Eq => Some(bool(difference == 0)),
+        Statement::Lt => Some(bool(difference < 0)),
+        Statement::Gt => Some(bool(difference > 0)),
+        _ => None,
+    }
+}
+
+/// `push constant a; push constant b; add` and the like become a push of the result, as does
+/// `neg` or `not` of a constant where the result fits in a single push.
+fn fold_constants(statements: &mut Vec<Spanned<Statement>>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i < statements.len() {
+        let window: Vec<_> = statements[i..].iter().take(3).map(|(s, _)| s).collect();
+        let folded = match window[..] {
+            [
+                Statement::Push(PushSource::Constant, x),
+                Statement::Push(PushSource::Constant, y),
+                op,
+                ..,
+            ] => evaluate(op, *x, *y).map(|value| (3, value)),
+            [Statement::Push(PushSource::Constant, x), Statement::Neg, ..] => {
+                Some((2, x.wrapping_neg()))
+            }
+            [Statement::Push(PushSource::Constant, x), Statement::Not, ..] => Some((2, !x)),
+            _ => None,
+        };
+        match folded {
+            Some((len, value)) if replace(statements, i, len, push_value(value)) => {
+                changed = true;
+                // The result may be the operand of the next operation
+                i = i.saturating_sub(1);
+            }
+            _ => i += 1,
+        }
+    }
+    changed
+}
+
+/// `not; not` leaves the value as it was.
+fn cancel_nots(statements: &mut Vec<Spanned<Statement>>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i + 1 < statements.len() {
+        if matches!(
+            (&statements[i].0, &statements[i + 1].0),
+            (Statement::Not, Statement::Not)
+        ) {
+            statements.drain(i..i + 2);
+            changed = true;
+        } else {
+            i += 1;
+        }
+    }
+    changed
+}
+
+/// A comparison, optionally negated, followed by `if-goto` becomes a single conditional jump
+/// on the difference of the operands. A `not` followed by `if-goto` jumps unless the value is
+/// -1, without computing the negation.
+fn fuse_branches(statements: &mut Vec<Spanned<Statement>>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i < statements.len() {
+        let window: Vec<_> = statements[i..].iter().take(3).map(|(s, _)| s).collect();
+        let jump = |statement: &Statement, negated: bool| match (statement, negated) {
+            (Statement::Eq, false) => Some(Jump::JEQ),
+            (Statement::Eq, true) => Some(Jump::JNE),
+            (Statement::Lt, false) => Some(Jump::JLT),
+            (Statement::Lt, true) => Some(Jump::JGE),
+            (Statement::Gt, false) => Some(Jump::JGT),
+            (Statement::Gt, true) => Some(Jump::JLE),
+            _ => None,
+        };
+        let fused = match window[..] {
+            [Statement::Not, Statement::IfGoto(label), ..] => {
+                Some((2, Statement::IfNotGoto(label.clone())))
+            }
+            [compare, Statement::Not, Statement::IfGoto(label)] => {
+                jump(compare, true).map(|jump| (3, Statement::IfCompare(jump, label.clone())))
+            }
+            [compare, Statement::IfGoto(label), ..] => {
+                jump(compare, false).map(|jump| (2, Statement::IfCompare(jump, label.clone())))
+            }
+            _ => None,
+        };
+        match fused {
+            Some((len, statement)) => {
+                replace(statements, i, len, vec![statement]);
+                changed = true;
+            }
+            None => i += 1,
+        }
+    }
+    changed
+}
+
+/// `push x; pop y` copies the value directly instead of through the stack.
+fn shortcut_stack(statements: &mut Vec<Spanned<Statement>>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i + 1 < statements.len() {
+        if let (Statement::Push(source, x), Statement::Pop(dest, y)) =
+            (&statements[i].0, &statements[i + 1].0)
+        {
+            let statement = Statement::Move(source.clone(), *x, dest.clone(), *y);
+            changed |= replace(statements, i, 2, vec![statement]);
+        }
+        i += 1;
+    }
+    changed
+}
+
+/// Statements after a `goto` or `return` are only reached through a label.
+fn remove_unreachable(statements: &mut Vec<Spanned<Statement>>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i < statements.len() {
+        if !matches!(statements[i].0, Statement::Goto(_) | Statement::Return) {
+            i += 1;
+            continue;
+        }
+        let end = statements[i + 1..]
+            .iter()
+            .position(|(s, _)| matches!(s, Statement::Label(_)))
+            .map_or(statements.len(), |n| i + 1 + n);
+        changed |= end > i + 1;
+        statements.drain(i + 1..end);
+        i += 1;
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{
+        CodeType,
+        assembly::{Jump, linker},
+        cpu::Cpu,
+        vm::{Lowering, Module, PushSource, Statement, VM},
+    };
+
+    /// Runs the program as it is, with bootstrap code if it has functions.
+    fn run(module: Module) -> Cpu {
+        let bootstrap = !module.ast.functions.is_empty();
+        let vm = VM {
+            modules: vec![module],
+            bootstrap,
+            optimize: false,
+            shared: Vec::new(),
+            lowering: Lowering::Stack,
+        };
+        let Ok((CodeType::Assembly(assembly), _)) = vm.compile() else {
+            panic!("translating failed");
+        };
+        let (hex, _) = linker::link(&[assembly.object().unwrap()]).unwrap();
+        let mut cpu = Cpu::new(&hex);
+        for (register, value) in [256, 300, 400, 3000, 3010].into_iter().enumerate() {
+            cpu.ram[register] = value;
+        }
+        for (i, value) in cpu.ram[300..320].iter_mut().enumerate() {
+            *value = (i as u16) * 7 + 1;
+        }
+        for (i, value) in cpu.ram[400..420].iter_mut().enumerate() {
+            *value = (i as u16) * 13 + 2;
+        }
+        cpu.run(10_000);
+        cpu
+    }
+
+    /// Runs the program with and without optimizing it and checks that both leave the
+    /// segments and the stack below SP the same. Returns the optimized statements.
+    fn check(src: &str) -> Vec<Statement> {
+        let path = Path::new("Sys.vm");
+        let mut optimized = Module::from_source(src, path).unwrap();
+        optimized.optimize();
+        let statements = optimized.statements().into_iter().cloned().collect();
+
+        let expected = run(Module::from_source(src, path).unwrap());
+        let actual = run(optimized);
+        // R13 to R15 are scratch registers
+        assert_eq!(expected.ram[..13], actual.ram[..13]);
+        let sp = expected.ram[0] as usize;
+        assert_eq!(expected.ram[16..sp], actual.ram[16..sp]);
+        assert_eq!(expected.ram[2048..], actual.ram[2048..]);
+        statements
+    }
+
+    #[test]
+    fn folds_constants() {
+        let statements = check(
+            "push constant 7
+            push constant 8
+            add
+            push constant 3
+            sub
+            push constant 0
+            push constant 5
+            sub
+            push constant 32767
+            push constant 1
+            add
+            push constant 10
+            push constant 20
+            lt
+            push constant 0
+            push constant 32767
+            gt
+            push constant 12
+            push constant 10
+            and
+            push constant 5
+            push constant 3
+            or
+            push constant 4
+            neg
+            push constant 0
+            neg
+            push constant 0
+            eq
+            ",
+        );
+        assert_eq!(
+            statements.len(),
+            14,
+            "expected every operation to be folded: {:?}",
+            statements
+        );
+        assert!(statements.iter().all(|s| matches!(
+            s,
+            Statement::Push(PushSource::Constant, _) | Statement::Neg | Statement::Not
+        )));
+    }
+
+    #[test]
+    fn cancels_double_negation() {
+        let statements = check(
+            "push local 2
+            not
+            not
+            push argument 3
+            not
+            not
+            not
+            add
+            ",
+        );
+        let nots = statements
+            .iter()
+            .filter(|s| matches!(s, Statement::Not))
+            .count();
+        assert_eq!(nots, 1);
+    }
+
+    #[test]
+    fn fuses_branches() {
+        let statements = check(
+            "push constant 0
+            pop local 0
+            label LOOP
+            push local 0
+            push constant 1
+            add
+            pop local 0
+            push local 0
+            push constant 10
+            lt
+            if-goto LOOP
+            push local 0
+            push argument 0
+            gt
+            not
+            if-goto SKIP
+            push constant 1
+            pop static 0
+            label SKIP
+            push argument 1
+            push argument 1
+            lt
+            not
+            if-goto EQUAL
+            push constant 4
+            pop static 3
+            label EQUAL
+            push argument 1
+            push argument 1
+            eq
+            not
+            if-goto NEVER
+            push local 3
+            not
+            not
+            if-goto NEVER
+            push constant 1
+            neg
+            pop local 1
+            push local 1
+            not
+            if-goto NEVER
+            push local 2
+            not
+            if-goto TAKEN
+            push constant 2
+            pop static 1
+            label TAKEN
+            push constant 3
+            pop static 2
+            label NEVER
+            ",
+        );
+        let fused: Vec<_> = statements
+            .iter()
+            .filter_map(|s| match s {
+                Statement::IfCompare(jump, _) => Some(jump.to_string()),
+                Statement::IfNotGoto(_) => Some("not".to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(fused, ["JLT", "JLE", "JGE", "JNE", "not", "not"]);
+        let unfused = statements
+            .iter()
+            .filter(|s| matches!(s, Statement::IfGoto(_) | Statement::Not))
+            .count();
+        assert_eq!(unfused, 1, "only the double negation keeps its if-goto");
+    }
+
+    #[test]
+    fn shortcuts_stack() {
+        let statements = check(
+            "push argument 1
+            pop this 2
+            push constant 7
+            pop temp 3
+            push local 4
+            pop static 0
+            push static 0
+            pop that 5
+            push constant 3500
+            pop pointer 1
+            push that 0
+            pop argument 0
+            push temp 3
+            pop local 0
+            push pointer 0
+            pop local 1
+            ",
+        );
+        assert_eq!(statements.len(), 8);
+        assert!(statements.iter().all(|s| matches!(s, Statement::Move(..))));
+    }
+
+    #[test]
+    fn removes_unreachable_code() {
+        let statements = check(
+            "function Sys.init 0
+            push constant 3
+            call Sys.double 1
+            pop static 0
+            goto END
+            push constant 1
+            pop static 1
+            label END
+            goto END
+            push constant 2
+            pop static 2
+            function Sys.double 0
+            push argument 0
+            push argument 0
+            add
+            return
+            push constant 99
+            pop static 3
+            ",
+        );
+        assert!(
+            !statements
+                .iter()
+                .any(|s| matches!(s, Statement::Push(PushSource::Constant, 1 | 2 | 99)))
+        );
+    }
+
+    #[test]
+    fn keeps_jumps_the_same() {
+        // Comparisons overflow like the CPU does, so -1 is not less than 32767 here
+        let statements = check(
+            "push constant 1
+            neg
+            pop local 0
+            push local 0
+            push constant 32767
+            lt
+            if-goto LESS
+            push constant 1
+            pop static 0
+            label LESS
+            ",
+        );
+        assert!(
+            statements
+                .iter()
+                .any(|s| matches!(s, Statement::IfCompare(Jump::JLT, _)))
+        );
+    }
+}
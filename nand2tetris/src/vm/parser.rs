@@ -1,17 +1,16 @@
 use chumsky::prelude::*;
 use text::{inline_whitespace, keyword, newline};
 
-use super::{Function, PopDest, PushSource, Statement};
+use super::{Ast, Function, PopDest, PushSource, Spanned, Statement};
 
 pub type Span = SimpleSpan;
 
 fn nl<'a>() -> impl Parser<'a, &'a str, (), extra::Err<Rich<'a, char, Span>>> {
-    let comment =
-        inline_whitespace().ignore_then(just("//").then(any().and_is(newline().not()).repeated()));
+    let comment = just("//").then(any().and_is(newline().not()).repeated());
 
-    let opt_comment_and_newline = comment
-        .repeated()
-        .at_most(1)
+    // Trailing whitespace is allowed with and without a comment
+    let opt_comment_and_newline = inline_whitespace()
+        .ignore_then(comment.or_not())
         .ignore_then(newline())
         .ignore_then(inline_whitespace());
 
@@ -20,8 +19,16 @@ fn nl<'a>() -> impl Parser<'a, &'a str, (), extra::Err<Rich<'a, char, Span>>> {
 
 fn int<'a>() -> impl Parser<'a, &'a str, u16, extra::Err<Rich<'a, char, Span>>> {
     text::int(10)
+        .validate(|s: &str, e, emitter| {
+            s.parse().unwrap_or_else(|_| {
+                emitter.emit(Rich::custom(
+                    e.span(),
+                    format!("Value {} does not fit in 16 bits", s),
+                ));
+                0
+            })
+        })
         .padded_by(inline_whitespace())
-        .map(|s: &str| u16::from_str_radix(s, 10).unwrap())
         .boxed()
 }
 
@@ -87,6 +94,10 @@ fn pop<'a>(
         keyword("static")
             .padded_by(inline_whitespace())
             .to(PopDest::Static(filename.to_string())),
+        // Rejected by validation, which can point at it
+        keyword("constant")
+            .padded_by(inline_whitespace())
+            .to(PopDest::Constant),
     ));
 
     keyword("pop")
@@ -103,21 +114,39 @@ fn branching<'a>() -> impl Parser<'a, &'a str, Statement, extra::Err<Rich<'a, ch
         keyword("label")
             .padded_by(inline_whitespace())
             .ignore_then(label)
-            .map(|label| Statement::Label(label)),
+            .map(Statement::Label),
         keyword("goto")
             .padded_by(inline_whitespace())
             .ignore_then(label)
-            .map(|label| Statement::Goto(label)),
+            .map(Statement::Goto),
         just("if-goto")
             .padded_by(inline_whitespace())
             .ignore_then(label)
-            .map(|label| Statement::IfGoto(label)),
+            .map(Statement::IfGoto),
     ))
 }
 
-pub fn statements<'a>(
+fn function_name<'a>()
+-> impl Parser<'a, &'a str, (String, String), extra::Err<Rich<'a, char, Span>>> {
+    text::ident()
+        .map(|s: &str| s.to_string())
+        .then_ignore(just('.'))
+        .then(text::ident().map(|s: &str| s.to_string()))
+}
+
+fn call<'a>() -> impl Parser<'a, &'a str, Statement, extra::Err<Rich<'a, char, Span>>> {
+    keyword("call")
+        .padded_by(inline_whitespace())
+        .ignore_then(function_name())
+        .map(|(filename, funcname)| format!("{}.{}", filename, funcname))
+        .then(int())
+        .map(|(name, args)| Statement::Call(name, args))
+        .boxed()
+}
+
+fn statements<'a>(
     filename: &str,
-) -> impl Parser<'a, &'a str, Vec<Statement>, extra::Err<Rich<'a, char, Span>>> {
+) -> impl Parser<'a, &'a str, Vec<Spanned<Statement>>, extra::Err<Rich<'a, char, Span>>> {
     let line = choice((
         keyword("not").to(Statement::Not),
         keyword("and").to(Statement::And),
@@ -132,22 +161,21 @@ pub fn statements<'a>(
         push(filename),
         pop(filename),
         branching(),
+        call(),
     ));
 
-    line.padded_by(nl()).repeated().collect()
+    line.map_with(|s, e| (s, e.span()))
+        .padded_by(nl())
+        .repeated()
+        .collect()
 }
 
-pub fn functions<'a>(
+fn functions<'a>(
     filename: &str,
 ) -> impl Parser<'a, &'a str, Vec<Function>, extra::Err<Rich<'a, char, Span>>> {
-    let name = text::ident()
-        .map(|s: &str| s.to_string())
-        .then_ignore(just('.'))
-        .then(text::ident().map(|s: &str| s.to_string()));
-
     let function = keyword("function")
         .padded_by(inline_whitespace())
-        .ignore_then(name)
+        .ignore_then(function_name())
         .validate(|(funcfilename, funcname), span, emitter| {
             if !filename.starts_with(&funcfilename) {
                 emitter.emit(Rich::custom(
@@ -162,13 +190,90 @@ pub fn functions<'a>(
         })
         .padded_by(inline_whitespace())
         .then(int())
+        .map_with(|header, e| (header, e.span()))
         .padded_by(nl())
         .then(statements(filename))
-        .map(|((name, locals), statements)| Function {
+        .map(|(((name, locals), span), statements)| Function {
             name,
             locals,
             statements,
+            span,
         });
 
     function.padded_by(nl()).repeated().collect()
 }
+
+/// A module is an optional block of statements outside any function, followed by functions.
+pub fn module<'a>(
+    filename: &str,
+) -> impl Parser<'a, &'a str, Ast, extra::Err<Rich<'a, char, Span>>> {
+    statements(filename)
+        .then(functions(filename))
+        .map(|(statements, functions)| Ast {
+            statements,
+            functions,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use chumsky::Parser;
+
+    use super::{Ast, module};
+
+    fn parse(src: &str) -> Ast {
+        module("Main.vm").parse(src).into_result().unwrap()
+    }
+
+    fn functions(ast: &Ast) -> Vec<(&str, u16, usize)> {
+        ast.functions
+            .iter()
+            .map(|f| (f.name.as_str(), f.locals, f.statements.len()))
+            .collect()
+    }
+
+    #[test]
+    fn statements_only() {
+        let ast = parse(
+            "// Not a function Main.f 0, only mentions one
+            push constant 1 // see function Main.g 2
+            pop static 0
+            ",
+        );
+        assert_eq!(ast.statements.len(), 2);
+        assert!(ast.functions.is_empty());
+    }
+
+    #[test]
+    fn prelude_and_functions() {
+        let ast = parse(
+            "push constant 1
+            pop temp 0
+
+            // function Main.main 1 follows
+            function Main.main 1
+            push local 0
+            return
+            function Main.f 0
+            push constant 0
+            return
+            ",
+        );
+        assert_eq!(ast.statements.len(), 2);
+        assert_eq!(functions(&ast), [("Main.main", 1, 2), ("Main.f", 0, 2)]);
+
+        let ast = parse("function Main.main 0\nreturn");
+        assert!(ast.statements.is_empty());
+        assert_eq!(functions(&ast), [("Main.main", 0, 1)]);
+        assert!(parse("").functions.is_empty());
+    }
+
+    #[test]
+    fn function_in_other_file() {
+        let errors = module("Main.vm")
+            .parse("function Other.f 0\nreturn\n")
+            .into_errors();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].to_string().contains("must match the filename"));
+    }
+}
@@ -0,0 +1,285 @@
+use std::{ops::Range, rc::Rc};
+
+use crate::{
+    assembly::MAX_LOAD,
+    diagnostic::{Diagnostic, Location},
+    memory::{HEAP_START, STACK_START, STATIC_SLOTS, VARIABLES_START},
+};
+
+use super::{Ast, PopDest, PushSource, Spanned, Statement};
+
+/// Number of values that fit on the stack.
+const STACK_SIZE: u16 = HEAP_START - STACK_START;
+
+/// The range of valid indices of a segment, and how to describe it.
+enum Bound {
+    Temp,
+    Pointer,
+    Static,
+    Constant,
+}
+
+impl Bound {
+    fn of_push(source: &PushSource) -> Option<Self> {
+        match source {
+            PushSource::Constant => Some(Bound::Constant),
+            PushSource::Static(_) => Some(Bound::Static),
+            PushSource::Temp => Some(Bound::Temp),
+            PushSource::Pointer => Some(Bound::Pointer),
+            PushSource::Local | PushSource::Argument | PushSource::This | PushSource::That => None,
+        }
+    }
+
+    fn of_pop(dest: &PopDest) -> Option<Self> {
+        match dest {
+            PopDest::Static(_) => Some(Bound::Static),
+            PopDest::Temp => Some(Bound::Temp),
+            PopDest::Pointer => Some(Bound::Pointer),
+            PopDest::Local
+            | PopDest::Argument
+            | PopDest::This
+            | PopDest::That
+            | PopDest::Constant => None,
+        }
+    }
+
+    fn max(&self) -> u16 {
+        match self {
+            Bound::Temp => 7,
+            Bound::Pointer => 1,
+            Bound::Static => STATIC_SLOTS as u16 - 1,
+            Bound::Constant => MAX_LOAD,
+        }
+    }
+
+    fn label(&self) -> String {
+        match self {
+            Bound::Constant => format!("the largest constant is {}", self.max()),
+            _ => format!("the largest valid index is {}", self.max()),
+        }
+    }
+
+    fn error(&self, index: u16) -> Diagnostic {
+        match self {
+            Bound::Temp => Diagnostic::error(
+                "segment-index",
+                format!("Index {} is outside the temp segment", index),
+            )
+            .with_note("temp has 8 entries, temp 0 to temp 7, kept in RAM 5 to 12"),
+            Bound::Pointer => Diagnostic::error(
+                "segment-index",
+                format!("Index {} is outside the pointer segment", index),
+            )
+            .with_note("pointer 0 is THIS and pointer 1 is THAT"),
+            Bound::Static => Diagnostic::error(
+                "segment-index",
+                format!("Index {} is outside the static segment", index),
+            )
+            .with_note(format!(
+                "each file can use static 0 to static {}, as statics live in RAM {} to {}",
+                self.max(),
+                VARIABLES_START,
+                STACK_START - 1
+            )),
+            Bound::Constant => Diagnostic::error(
+                "constant-range",
+                format!("Constant {} does not fit in 15 bits", index),
+            )
+            .with_note(format!(
+                "constants range from 0 to {}; push larger values with arithmetic, such as `neg` or `not`",
+                MAX_LOAD
+            )),
+        }
+    }
+}
+
+/// The byte ranges of the whitespace separated words of a statement.
+fn words(src: &str, span: Range<usize>) -> Vec<Range<usize>> {
+    let mut words = Vec::new();
+    let mut start = None;
+    for (i, c) in src[span.clone()].char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(s)) => {
+                words.push(span.start + s..span.start + i);
+                start = None;
+            }
+            (false, None) => start = Some(i),
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        words.push(span.start + s..span.end);
+    }
+    words
+}
+
+/// An error for a count of values on the stack, such as arguments or locals, that can not fit.
+fn stack_error(
+    what: &str,
+    count: u16,
+    src: &str,
+    file: &Rc<str>,
+    span: Range<usize>,
+) -> Diagnostic {
+    // The count is the last word of the statement
+    let location = words(src, span.clone()).pop().unwrap_or(span);
+    Diagnostic::error(
+        "stack-size",
+        format!("{} {} do not fit on the stack", count, what),
+    )
+    .with_location(Location::new(file, location))
+    .with_label(format!("the stack has room for {} values", STACK_SIZE))
+    .with_note(format!(
+        "the stack runs from RAM {} to {}",
+        STACK_START,
+        HEAP_START - 1
+    ))
+}
+
+fn validate_statements(
+    statements: &[Spanned<Statement>],
+    src: &str,
+    file: &Rc<str>,
+) -> Vec<Diagnostic> {
+    let mut errors = Vec::new();
+    for (statement, span) in statements {
+        let (bound, index) = match statement {
+            Statement::Push(source, index) => (Bound::of_push(source), *index),
+            Statement::Pop(PopDest::Constant, _) => {
+                // `pop constant index`, pointing at the segment
+                let location = words(src, span.into_range())
+                    .get(1)
+                    .cloned()
+                    .unwrap_or_else(|| span.into_range());
+                errors.push(
+                    Diagnostic::error(
+                        "read-only-segment",
+                        "Cannot pop into the constant segment, which is read-only",
+                    )
+                    .with_location(Location::new(file, location))
+                    .with_note("use `pop temp 0` to discard the top of the stack"),
+                );
+                continue;
+            }
+            Statement::Pop(dest, index) => (Bound::of_pop(dest), *index),
+            Statement::Call(_, args) if *args > STACK_SIZE => {
+                errors.push(stack_error(
+                    "arguments",
+                    *args,
+                    src,
+                    file,
+                    span.into_range(),
+                ));
+                continue;
+            }
+            _ => continue,
+        };
+        let Some(bound) = bound.filter(|bound| index > bound.max()) else {
+            continue;
+        };
+        // `push segment index`, the index being the last word
+        let location = words(src, span.into_range())
+            .pop()
+            .unwrap_or_else(|| span.into_range());
+        errors.push(
+            bound
+                .error(index)
+                .with_location(Location::new(file, location))
+                .with_label(bound.label()),
+        );
+    }
+    errors
+}
+
+impl Ast {
+    /// Checks that every segment index is in range and that calls and functions fit on the
+    /// stack, so translating never has to guess what a statement means. Reports every
+    /// violation in the module.
+    pub(super) fn validate(&self, src: &str, file: &Rc<str>) -> Vec<Diagnostic> {
+        let mut errors = validate_statements(&self.statements, src, file);
+        for function in &self.functions {
+            if function.locals > STACK_SIZE {
+                errors.push(stack_error(
+                    "locals",
+                    function.locals,
+                    src,
+                    file,
+                    function.span.into_range(),
+                ));
+            }
+            errors.append(&mut validate_statements(&function.statements, src, file));
+        }
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::vm::Module;
+
+    /// The code of each error in the source, with the text it points at.
+    fn errors(src: &str) -> Vec<(&'static str, &str)> {
+        let Err(errors) = Module::from_source(src, Path::new("Test.vm")) else {
+            panic!("validation passed");
+        };
+        errors
+            .iter()
+            .map(|e| (e.code, &src[e.location.as_ref().unwrap().span.clone()]))
+            .collect()
+    }
+
+    #[test]
+    fn in_range() {
+        let src = "push temp 7\npop pointer 1\npush static 239\npush constant 32767\n";
+        assert!(Module::from_source(src, Path::new("Test.vm")).is_ok());
+    }
+
+    #[test]
+    fn segment_indices() {
+        assert_eq!(errors("push temp 8\n"), [("segment-index", "8")]);
+        assert_eq!(errors("pop temp 9 // comment\n"), [("segment-index", "9")]);
+        assert_eq!(errors("push pointer 2\n"), [("segment-index", "2")]);
+        assert_eq!(errors("pop  static   240\n"), [("segment-index", "240")]);
+        assert_eq!(
+            errors("push constant 40000\n"),
+            [("constant-range", "40000")]
+        );
+    }
+
+    #[test]
+    fn pop_constant() {
+        assert_eq!(
+            errors("pop constant 3\n"),
+            [("read-only-segment", "constant")]
+        );
+        assert_eq!(
+            errors("pop   constant 3 // comment\n"),
+            [("read-only-segment", "constant")]
+        );
+    }
+
+    #[test]
+    fn stack_size() {
+        assert_eq!(errors("call Test.f 65535\n"), [("stack-size", "65535")]);
+        assert_eq!(
+            errors("function Test.f 40000\nreturn\n"),
+            [("stack-size", "40000")]
+        );
+    }
+
+    #[test]
+    fn reports_every_violation() {
+        let src = "push temp 9\npop constant 1\nfunction Test.f 0\npop pointer 2\npush static 300\nreturn\n";
+        assert_eq!(
+            errors(src),
+            [
+                ("segment-index", "9"),
+                ("read-only-segment", "constant"),
+                ("segment-index", "2"),
+                ("segment-index", "300"),
+            ]
+        );
+    }
+}
@@ -12,7 +12,8 @@ D=M
 M=D
 @2
 M=0
-(LOOP) @counter
+(LOOP)
+@counter
 D=M
 @12345
 D;JEQ